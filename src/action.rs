@@ -1,4 +1,4 @@
-use crate::error::ActionError;
+use crate::error::{ActionError, Span};
 use crate::expr::{Expr, Op};
 use crate::value::Value;
 
@@ -7,16 +7,107 @@ pub type AcResult = Result<(AcReturn, Value), ActionError>;
 pub enum AcReturn {
     No,
     Func,
+    Break,
+    Continue,
     //Expr,
 }
 
+/// The value of a block run outside of any loop, where a break or continue has nothing to leave
+pub fn outside_loop(r: AcResult) -> Result<Value, ActionError> {
+    match r? {
+        (AcReturn::Break, _) | (AcReturn::Continue, _) => Err(ActionError::NotInLoop),
+        (_, v) => Ok(v),
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum Action {
     Select(Expr),
     SetSelect(Expr, Expr),
+    /// A Select or SetSelect that only lasts for its block
+    SelectBlock(Box<Action>, Vec<Action>),
+    /// Back out one selection
+    Pop,
+    /// Collect garbage once the current top level action is done
+    Gc,
+    /// `gc every 500` collects after that many allocations, 0 for never
+    GcEvery(Expr),
+    /// Show memory use and anything leaked, once the current top level action is done
+    Mem,
+    /// Back out of all selections
+    SelectRoot,
+    Mark(String),
+    GotoMark(String),
+    Delete(Expr),
+    Move(Expr, Expr),
+    Rename(Expr, String),
+    /// `spawn 5 Rat` makes Rat1 to Rat5, each a deep copy of Rat
+    Spawn(Expr, Expr),
     OpSet(Op, Expr, Expr),
     Set(Expr, Expr),
+    /// `a -> b` reads whatever is at b each time a is read
+    Link(Expr, Expr),
     AddItem(isize, String),
     Resolve(Expr),
     Return(Expr),
+    Seed(Option<Expr>),
+    Break,
+    Continue,
+    /// `## text` on the lines above a field being set
+    Doc(String, Box<Action>),
+    At(Span, Box<Action>),
+}
+
+impl Action {
+    /// The action without any location wrapper
+    pub fn inner(&self) -> &Action {
+        match self {
+            Action::At(_, a) => a.inner(),
+            a => a,
+        }
+    }
+
+    /// Points every span in the action at the given source
+    pub fn set_src(&mut self, src: usize) {
+        use Action::*;
+        match self {
+            Select(e)
+            | Resolve(e)
+            | Return(e)
+            | Seed(Some(e))
+            | GcEvery(e)
+            | Delete(e)
+            | Rename(e, _) => e.set_src(src),
+            SetSelect(a, b)
+            | OpSet(_, a, b)
+            | Set(a, b)
+            | Link(a, b)
+            | Move(a, b)
+            | Spawn(a, b) => {
+                a.set_src(src);
+                b.set_src(src);
+            }
+            SelectBlock(a, body) => {
+                a.set_src(src);
+                for b in body {
+                    b.set_src(src);
+                }
+            }
+            Doc(_, a) => a.set_src(src),
+            At(sp, a) => {
+                sp.src = src;
+                a.set_src(src);
+            }
+            AddItem(_, _)
+            | Seed(None)
+            | Break
+            | Continue
+            | Pop
+            | Gc
+            | Mem
+            | SelectRoot
+            | Mark(_)
+            | GotoMark(_) => {}
+        }
+    }
 }
@@ -1,56 +1,126 @@
+use crate::api_std;
+use crate::dice::{Dice, MAX_SIDES};
 use crate::error::ActionError;
+use crate::expr::Expr;
+use crate::proto::Proto;
 use crate::scope::Scope;
 use crate::value::Value;
 
-pub fn run_api_expr(fname:&str,scope:&Scope,params:&[Value])->Option<Result<Option<Value>,ActionError>>{
-    scope.on_wrap(|wrap|{run_api_func(fname,wrap,params)})
+pub fn run_api_expr(
+    fname: &str,
+    scope: &mut Scope,
+    params: &[Value],
+) -> Option<Result<Option<Value>, ActionError>> {
+    scope.on_wrap(|wrap| run_api_func(fname, wrap, params))
 }
 
-pub fn run_api_func(fname:&str,scope:&mut Scope,params:&[Value])->Option<Result<Option<Value>,ActionError>>{
-    Some(match fname{
-        "d" => d(scope,params),
-        "foreach" => for_each(scope,&params),
-        "fold" => fold(scope,&params),
-        "load" => load(scope,&params),
-        "if" => if_expr(scope,&params),
-        "link" => link(scope,&params),
-        _ => {return None}
+pub fn run_api_func(
+    fname: &str,
+    scope: &mut Scope,
+    params: &[Value],
+) -> Option<Result<Option<Value>, ActionError>> {
+    Some(match fname {
+        "d" => d(scope, params),
+        "foreach" => for_each(scope, &params),
+        "fold" => fold(scope, &params),
+        "load" => load(scope, &params),
+        "if" => if_expr(scope, &params),
+        "link" => link(scope, &params),
+        "doc" => doc(scope, &params),
+        "len" => api_std::len(scope, params),
+        "keys" => api_std::keys(scope, params),
+        "values" => api_std::values(scope, params),
+        "contains" => api_std::contains(scope, params),
+        "sort" => api_std::sort(scope, params),
+        "sort_by" => api_std::sort_by(scope, params),
+        "reverse" => api_std::reverse(scope, params),
+        "sum" => api_std::sum(scope, params),
+        "min" => api_std::min(scope, params),
+        "max" => api_std::max(scope, params),
+        "filter" => api_std::filter(scope, params),
+        "map" => api_std::map(scope, params),
+        "find" => api_std::find(scope, params),
+        "join" => api_std::join(scope, params),
+        "split" => api_std::split(scope, params),
+        "upper" => api_std::upper(scope, params),
+        "lower" => api_std::lower(scope, params),
+        "clone" => api_std::clone(scope, params),
+        _ => return None,
     })
 }
 
-pub fn d(_sc: &mut Scope, params: &[Value]) -> Result<Option<Value>, ActionError> {
+pub fn d(sc: &mut Scope, params: &[Value]) -> Result<Option<Value>, ActionError> {
     let mut res = 0;
     for p in params {
         match p {
-            Value::Num(n) => res += rand::random::<i32>().abs() % n + 1,
-            _ => return Err(ActionError::new("d needs num sides")),
+            Value::Num(n) if *n > 0 && *n as usize <= MAX_SIDES => {
+                let r = Dice::new(1, *n as usize).roll(sc.rng_mut());
+                res = res.checked_add(r.total()).ok_or(ActionError::Overflow)?;
+                sc.push_roll(r);
+            }
+            Value::Num(n) => {
+                return Err(ActionError::DiceSides {
+                    max: MAX_SIDES,
+                    got: *n,
+                })
+            }
+            v => return Err(ActionError::wrong_type("positive num", v)),
         }
     }
     Ok(Some(Value::Num(res)))
 }
 
-pub fn link(_sc: &mut Scope, params: &[Value]) -> Result<Option<Value>, ActionError> {
-    match params.get(0) {
-        Some(Value::Proto(p)) => Ok(Some(Value::Proto(p.with_deref(1)))),
-        _ => Err(ActionError::new("can only link on protos")),
+/// A path written as a str, eg "Armory.Sword"
+fn path_arg(sc: &mut Scope, v: &Value) -> Result<Proto, ActionError> {
+    match v {
+        //kept as a source only when bad, for the error to point into
+        Value::Str(s) => match s.parse::<Expr>() {
+            Ok(ex) => ex.eval_path(sc),
+            Err(e) => Err(ActionError::ParseErr(sc.add_source("<path>", s), e)),
+        },
+        v => Err(ActionError::wrong_type("str path", v)),
     }
 }
 
+/// link("Armory.Sword") gives the same link as `-> Armory.Sword`
+pub fn link(sc: &mut Scope, params: &[Value]) -> Result<Option<Value>, ActionError> {
+    let p1 = params.get(0).ok_or(ActionError::Arity {
+        expected: 1,
+        got: 0,
+    })?;
+    let target = path_arg(sc, p1)?;
+    Ok(Some(sc.link_to(&target)))
+}
+
+/// doc("Rat.HP") gives the `##` comment written above Rat.HP, or null
+pub fn doc(sc: &mut Scope, params: &[Value]) -> Result<Option<Value>, ActionError> {
+    let p1 = params.get(0).ok_or(ActionError::Arity {
+        expected: 1,
+        got: 0,
+    })?;
+    let target = path_arg(sc, p1)?;
+    Ok(Some(match sc.get_doc(&target) {
+        Some(v) => v.clone_weak(),
+        None => Value::Null,
+    }))
+}
+
 pub fn load(sc: &mut Scope, params: &[Value]) -> Result<Option<Value>, ActionError> {
     //param order fname, target
-    let p1 = params.get(0).ok_or(ActionError::new("d needs num sides"))?;
+    let p1 = params.get(0).ok_or(ActionError::Arity {
+        expected: 1,
+        got: 0,
+    })?;
     let fv = match p1 {
         Value::Str(s) => s,
-        _ => return Err(ActionError::new("filename should be string")),
+        v => return Err(ActionError::wrong_type("str", v)),
     };
     match params.get(1) {
-        Some(Value::Proto(p)) => {
-            let new_sc = Scope::from_file(fv)?;
-            sc.set(p, new_sc.eat_data())?;
+        Some(t) => {
+            let p = path_arg(sc, t)?;
+            sc.load_into(fv, &p)?;
             Ok(None)
         }
-        Some(_) => Err(ActionError::new("target should be proto")),
-
         None => {
             sc.run_file(fv)?;
             Ok(None)
@@ -58,23 +128,30 @@ pub fn load(sc: &mut Scope, params: &[Value]) -> Result<Option<Value>, ActionErr
     }
 }
 
-pub fn if_expr(_sc: &mut Scope, params: &[Value]) -> Result<Option<Value>, ActionError> {
+pub fn if_expr(sc: &mut Scope, params: &[Value]) -> Result<Option<Value>, ActionError> {
     if params.len() < 3 {
-        return Err(ActionError::new("if requires 3 params"));
+        return Err(ActionError::Arity {
+            expected: 3,
+            got: params.len(),
+        });
     }
     match params[0] {
-        Value::Bool(true) => Ok(Some(params[1].clone())),
-        Value::Num(n) if n > 0 => Ok(Some(params[1].clone())),
-        _ => Ok(Some(params[2].clone())),
+        Value::Bool(true) => Ok(Some(params[1].clone_shallow(sc.gm_mut()))),
+        Value::Num(n) if n > 0 => Ok(Some(params[1].clone_shallow(sc.gm_mut()))),
+        _ => Ok(Some(params[2].clone_shallow(sc.gm_mut()))),
     }
 }
 
 pub fn fold(sc: &mut Scope, params: &[Value]) -> Result<Option<Value>, ActionError> {
     match params.len() {
-        n if n < 3 => Err(ActionError::new(
-            "Fold requires 3 params : foldvar,iterble,func",
-        )),
-        _ => fold_each(sc, params.get(0).map(|n| n.clone()), &params[1..]),
+        n if n < 3 => Err(ActionError::Arity {
+            expected: 3,
+            got: n,
+        }),
+        _ => {
+            let acc = params[0].clone_shallow(sc.gm_mut());
+            fold_each(sc, Some(acc), &params[1..])
+        }
     }
 }
 
@@ -88,24 +165,24 @@ fn fold_each(
     fold: Option<Value>,
     params: &[Value],
 ) -> Result<Option<Value>, ActionError> {
-    if params.len() <= 1 {
-        return Err(ActionError::new("FoldEach requires at least 2 params"));
+    if params.len() != 2 {
+        if let Some(f) = fold {
+            sc.gm_mut().drop(f);
+        }
+        return Err(ActionError::Arity {
+            expected: 2,
+            got: params.len(),
+        });
     }
-    if params.len() == 2 {
-        match params[0] {
-            Value::List(ref l) => {
-                return sc.for_each(l.clone().into_iter().enumerate(), fold, params[1].clone())
-            }
-            Value::Num(n) => {
-                return sc.for_each(
-                    (0..n).map(|x| Value::Num(x)).enumerate(),
-                    fold,
-                    params[1].clone(),
-                )
+    let it = params[0].clone_shallow(sc.gm_mut());
+    let items = match sc.loop_items(it) {
+        Ok(items) => items,
+        Err(e) => {
+            if let Some(f) = fold {
+                sc.gm_mut().drop(f);
             }
-            _ => return Err(ActionError::new("Must be list for iterator right now")),
+            return Err(e);
         }
-    }
-
-    Ok(None)
+    };
+    sc.for_each(items.into_iter(), fold, params[1].clone_weak())
 }
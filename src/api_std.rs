@@ -0,0 +1,443 @@
+//! List, map and string builtins, called through the api_funcs table
+use crate::ecs_ish::GenData;
+use crate::error::ActionError;
+use crate::proto::ProtoNode;
+use crate::scope::Scope;
+use crate::value::Value;
+use std::cmp::Ordering;
+
+type ApiRes = Result<Option<Value>, ActionError>;
+
+fn arg<'a>(params: &'a [Value], n: usize) -> Result<&'a Value, ActionError> {
+    params.get(n).ok_or(ActionError::Arity {
+        expected: n + 1,
+        got: params.len(),
+    })
+}
+
+fn resolve<'a>(sc: &'a Scope, v: &'a Value) -> Result<&'a Value, ActionError> {
+    v.deref(sc.gm())
+}
+
+/// The (weak) items of a list, or the values of a map
+fn items(sc: &Scope, v: &Value) -> Result<Vec<GenData>, ActionError> {
+    match resolve(sc, v)? {
+        Value::List(l) => Ok(l.iter().map(|g| g.clone_weak()).collect()),
+        Value::Map(m) => Ok(m
+            .iter()
+            .filter(|(k, _)| !k.is_meta())
+            .map(|(_, g)| g.clone_weak())
+            .collect()),
+        v => Err(ActionError::wrong_type("list or map", v)),
+    }
+}
+
+fn str_arg(sc: &Scope, params: &[Value], n: usize) -> Result<String, ActionError> {
+    match resolve(sc, arg(params, n)?)? {
+        Value::Str(s) => Ok(s.clone()),
+        v => Err(ActionError::wrong_type("str", v)),
+    }
+}
+
+/// A strong copy of the value at the end of any references
+fn value_of(sc: &mut Scope, g: &GenData) -> Result<Value, ActionError> {
+    let v = sc.gm().get(g).ok_or(ActionError::FreedReference)?;
+    let v = resolve(sc, v)?.clone_weak();
+    Ok(v.to_strong(sc.gm_mut()))
+}
+
+fn strong_list(sc: &mut Scope, l: Vec<GenData>) -> Value {
+    Value::List(l.into_iter().map(|g| g.to_strong(sc.gm_mut())).collect())
+}
+
+fn drop_all(sc: &mut Scope, l: Vec<GenData>) {
+    for g in l {
+        sc.gm_mut().drop_ref(g);
+    }
+}
+
+/// Calls f with a reference to the item
+fn call_on(sc: &mut Scope, f: &Value, g: &GenData) -> Result<Value, ActionError> {
+    let r = Value::Ref(g.clone_strong(sc.gm_mut()));
+    let v = sc.call_func(f.clone_weak(), None, vec![r])?;
+    Ok(sc.deref_scalar(v))
+}
+
+fn call_bool(sc: &mut Scope, f: &Value, g: &GenData) -> Result<bool, ActionError> {
+    match call_on(sc, f, g)? {
+        Value::Bool(b) => Ok(b),
+        v => {
+            let e = ActionError::wrong_type("bool", &v);
+            sc.gm_mut().drop(v);
+            Err(e)
+        }
+    }
+}
+
+/// list.1..3, list.-2.. and "str".0..=2, bounds past either end are clamped
+pub fn slice(
+    sc: &mut Scope,
+    v: Value,
+    a: isize,
+    b: Option<isize>,
+    inc: bool,
+) -> Result<Value, ActionError> {
+    let bound = |n: isize, len: usize| match n {
+        n if n < 0 => len.saturating_sub(-n as usize),
+        n => std::cmp::min(n as usize, len),
+    };
+    let res = match resolve(sc, &v) {
+        Ok(Value::List(l)) => {
+            let start = bound(a, l.len());
+            let end = match b {
+                Some(b) => std::cmp::min(bound(b, l.len()) + inc as usize, l.len()),
+                None => l.len(),
+            };
+            let part = match start < end {
+                true => l[start..end].iter().map(|g| g.clone_weak()).collect(),
+                false => Vec::new(),
+            };
+            Ok(part)
+        }
+        Ok(Value::Str(s)) => {
+            let cs: Vec<char> = s.chars().collect();
+            let start = bound(a, cs.len());
+            let end = match b {
+                Some(b) => std::cmp::min(bound(b, cs.len()) + inc as usize, cs.len()),
+                None => cs.len(),
+            };
+            let s = match start < end {
+                true => cs[start..end].iter().collect(),
+                false => String::new(),
+            };
+            sc.gm_mut().drop(v);
+            return Ok(Value::Str(s));
+        }
+        Ok(ov) => Err(ActionError::wrong_type("list or str", ov)),
+        Err(e) => Err(e),
+    };
+    let res = res.map(|part| strong_list(sc, part));
+    sc.gm_mut().drop(v);
+    res
+}
+
+pub fn len(sc: &mut Scope, params: &[Value]) -> ApiRes {
+    let n = match resolve(sc, arg(params, 0)?)? {
+        Value::List(l) => l.len(),
+        Value::Map(m) => m.keys().filter(|k| !k.is_meta()).count(),
+        Value::Str(s) => s.chars().count(),
+        v => return Err(ActionError::wrong_type("list, map or str", v)),
+    };
+    Ok(Some(Value::from(n)))
+}
+
+pub fn keys(sc: &mut Scope, params: &[Value]) -> ApiRes {
+    let ks: Vec<Value> = match resolve(sc, arg(params, 0)?)? {
+        Value::List(l) => (0..l.len()).map(Value::from).collect(),
+        Value::Map(m) => m
+            .keys()
+            .filter(|k| !k.is_meta())
+            .map(|k| Value::Str(k.as_string()))
+            .collect(),
+        v => return Err(ActionError::wrong_type("list or map", v)),
+    };
+    Ok(Some(Value::List(
+        ks.into_iter().map(|k| sc.push_mem(k)).collect(),
+    )))
+}
+
+pub fn values(sc: &mut Scope, params: &[Value]) -> ApiRes {
+    let l = items(sc, arg(params, 0)?)?;
+    Ok(Some(strong_list(sc, l)))
+}
+
+/// A list item equal to x, a map key named x, or a substring x
+pub fn contains(sc: &mut Scope, params: &[Value]) -> ApiRes {
+    let x = resolve(sc, arg(params, 1)?)?;
+    let res = match resolve(sc, arg(params, 0)?)? {
+        Value::List(l) => {
+            let mut found = false;
+            for v in l.iter().filter_map(|g| sc.gm().get(g)) {
+                if v.deep_eq(x, sc.gm())? {
+                    found = true;
+                    break;
+                }
+            }
+            found
+        }
+        Value::Map(m) => match x {
+            Value::Str(s) => m.contains_key(&ProtoNode::str(s)),
+            _ => false,
+        },
+        Value::Str(s) => match x {
+            Value::Str(x) => s.contains(x.as_str()),
+            _ => false,
+        },
+        v => return Err(ActionError::wrong_type("list, map or str", v)),
+    };
+    Ok(Some(Value::Bool(res)))
+}
+
+fn sort_gds(sc: &Scope, l: &mut Vec<GenData>) -> Result<(), ActionError> {
+    let mut err = None;
+    l.sort_by(|a, b| {
+        let (av, bv) = match (sc.gm().get(a), sc.gm().get(b)) {
+            (Some(av), Some(bv)) => (av, bv),
+            _ => return Ordering::Equal,
+        };
+        match av.deep_cmp(bv, sc.gm()) {
+            Ok(o) => o,
+            Err(e) => {
+                err.get_or_insert(e);
+                Ordering::Equal
+            }
+        }
+    });
+    match err {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+pub fn sort(sc: &mut Scope, params: &[Value]) -> ApiRes {
+    let mut l = items(sc, arg(params, 0)?)?;
+    sort_gds(sc, &mut l)?;
+    Ok(Some(strong_list(sc, l)))
+}
+
+/// Sorts by the key f gives for each item
+pub fn sort_by(sc: &mut Scope, params: &[Value]) -> ApiRes {
+    let l = items(sc, arg(params, 0)?)?;
+    let f = arg(params, 1)?;
+    let mut keyed = Vec::new();
+    for g in l {
+        match call_on(sc, f, &g) {
+            Ok(k) => keyed.push((k, g)),
+            Err(e) => {
+                for (k, _) in keyed {
+                    sc.gm_mut().drop(k);
+                }
+                return Err(e);
+            }
+        }
+    }
+    let mut err = None;
+    keyed.sort_by(|(a, _), (b, _)| match a.deep_cmp(b, sc.gm()) {
+        Ok(o) => o,
+        Err(e) => {
+            err.get_or_insert(e);
+            Ordering::Equal
+        }
+    });
+    let mut res = Vec::new();
+    for (k, g) in keyed {
+        sc.gm_mut().drop(k);
+        res.push(g);
+    }
+    match err {
+        Some(e) => Err(e),
+        None => Ok(Some(strong_list(sc, res))),
+    }
+}
+
+pub fn reverse(sc: &mut Scope, params: &[Value]) -> ApiRes {
+    if let Value::Str(s) = resolve(sc, arg(params, 0)?)? {
+        return Ok(Some(Value::Str(s.chars().rev().collect())));
+    }
+    let mut l = items(sc, arg(params, 0)?)?;
+    l.reverse();
+    Ok(Some(strong_list(sc, l)))
+}
+
+pub fn sum(sc: &mut Scope, params: &[Value]) -> ApiRes {
+    let mut res = 0;
+    for g in items(sc, arg(params, 0)?)? {
+        let v = sc.gm().get(&g).ok_or(ActionError::FreedReference)?;
+        match resolve(sc, v)? {
+            Value::Num(n) => res += n,
+            v => return Err(ActionError::wrong_type("num", v)),
+        }
+    }
+    Ok(Some(Value::Num(res)))
+}
+
+/// The first of the items that ord puts at the front, Null if there are none
+fn best(sc: &mut Scope, params: &[Value], ord: Ordering) -> ApiRes {
+    let mut l = items(sc, arg(params, 0)?)?;
+    sort_gds(sc, &mut l)?;
+    if ord == Ordering::Greater {
+        l.reverse();
+    }
+    match l.first() {
+        Some(g) => value_of(sc, g).map(Some),
+        None => Ok(Some(Value::Null)),
+    }
+}
+
+pub fn min(sc: &mut Scope, params: &[Value]) -> ApiRes {
+    best(sc, params, Ordering::Less)
+}
+
+pub fn max(sc: &mut Scope, params: &[Value]) -> ApiRes {
+    best(sc, params, Ordering::Greater)
+}
+
+/// The items f returns true for
+pub fn filter(sc: &mut Scope, params: &[Value]) -> ApiRes {
+    let l = items(sc, arg(params, 0)?)?;
+    let f = arg(params, 1)?;
+    let mut res = Vec::new();
+    for g in l {
+        match call_bool(sc, f, &g) {
+            Ok(true) => res.push(g.clone_strong(sc.gm_mut())),
+            Ok(false) => {}
+            Err(e) => {
+                drop_all(sc, res);
+                return Err(e);
+            }
+        }
+    }
+    Ok(Some(Value::List(res)))
+}
+
+pub fn map(sc: &mut Scope, params: &[Value]) -> ApiRes {
+    let l = items(sc, arg(params, 0)?)?;
+    let f = arg(params, 1)?;
+    let mut res = Vec::new();
+    for g in l {
+        match call_on(sc, f, &g) {
+            Ok(v) => res.push(sc.push_mem(v)),
+            Err(e) => {
+                drop_all(sc, res);
+                return Err(e);
+            }
+        }
+    }
+    Ok(Some(Value::List(res)))
+}
+
+/// The first item f returns true for, Null if there isn't one
+pub fn find(sc: &mut Scope, params: &[Value]) -> ApiRes {
+    let l = items(sc, arg(params, 0)?)?;
+    let f = arg(params, 1)?;
+    for g in l {
+        if call_bool(sc, f, &g)? {
+            return value_of(sc, &g).map(Some);
+        }
+    }
+    Ok(Some(Value::Null))
+}
+
+pub fn join(sc: &mut Scope, params: &[Value]) -> ApiRes {
+    let sep = match params.get(1) {
+        Some(_) => str_arg(sc, params, 1)?,
+        None => String::new(),
+    };
+    let mut parts = Vec::new();
+    for g in items(sc, arg(params, 0)?)? {
+        let v = sc.gm().get(&g).ok_or(ActionError::FreedReference)?;
+        parts.push(match resolve(sc, v)? {
+            Value::Str(s) => s.clone(),
+            v => v.print(0, sc.gm()),
+        });
+    }
+    Ok(Some(Value::Str(parts.join(&sep))))
+}
+
+pub fn split(sc: &mut Scope, params: &[Value]) -> ApiRes {
+    let s = str_arg(sc, params, 0)?;
+    let sep = str_arg(sc, params, 1)?;
+    let parts: Vec<GenData> = s
+        .split(sep.as_str())
+        .map(|p| sc.push_mem(Value::str(p)))
+        .collect();
+    Ok(Some(Value::List(parts)))
+}
+
+pub fn upper(sc: &mut Scope, params: &[Value]) -> ApiRes {
+    Ok(Some(Value::Str(str_arg(sc, params, 0)?.to_uppercase())))
+}
+
+pub fn lower(sc: &mut Scope, params: &[Value]) -> ApiRes {
+    Ok(Some(Value::Str(str_arg(sc, params, 0)?.to_lowercase())))
+}
+
+/// A copy sharing no state with the original.
+/// References, such as protos, are still shared unless the second argument is true
+pub fn clone(sc: &mut Scope, params: &[Value]) -> ApiRes {
+    let refs = match params.get(1) {
+        None => false,
+        Some(v) => match resolve(sc, v)? {
+            Value::Bool(b) => *b,
+            v => return Err(ActionError::wrong_type("bool", v)),
+        },
+    };
+    let v = resolve(sc, arg(params, 0)?)?.clone_weak();
+    let tmp = sc.gm_mut().push(v);
+    let c = sc.gm_mut().deep_clone(&tmp, refs);
+    sc.gm_mut().drop_ref(tmp);
+    let c = c.ok_or(ActionError::FreedReference)?;
+    let res = value_of(sc, &c);
+    sc.gm_mut().drop_ref(c);
+    res.map(Some)
+}
+
+#[cfg(test)]
+mod test_api_std {
+    use super::*;
+    use crate::expr::Expr;
+
+    fn eval(sc: &mut Scope, s: &str) -> Value {
+        let e: Expr = s.parse().unwrap();
+        let v = e.eval(sc).unwrap();
+        sc.deref_scalar(v)
+    }
+
+    #[test]
+    fn test_builtins() {
+        let mut sc = Scope::new();
+        sc.handle_input(
+            "l = [3,1,2]\nm = {b:2,a:1}\nrats = [{HP:5},{HP:2},{HP:9}]\nn = 3\nsum = 100\n",
+        )
+        .unwrap();
+        let cases = [
+            ("len(l)", Value::Num(3)),
+            ("len(m)", Value::Num(2)),
+            ("len(\"abc\")", Value::Num(3)),
+            ("join(keys(m), \",\")", Value::str("a,b")),
+            ("sum(values(m))", Value::Num(3)),
+            ("contains(l, 2)", Value::Bool(true)),
+            ("contains(m, \"c\")", Value::Bool(false)),
+            ("join(sort(l), \"\")", Value::str("123")),
+            ("join(sort([\"b\",\"c\",\"a\"]), \"\")", Value::str("abc")),
+            (
+                "sort([[2],[1,5],[1]]) == [[1],[1,5],[2]]",
+                Value::Bool(true),
+            ),
+            ("join(reverse(l), \"\")", Value::str("213")),
+            ("reverse(\"abc\")", Value::str("cba")),
+            ("min(l) + max(l)", Value::Num(4)),
+            ("sum(map(l, fn(x){ x * 2 }))", Value::Num(12)),
+            ("len(filter(rats, fn(r){ r.HP > 3 }))", Value::Num(2)),
+            ("find(l, fn(x){ x < 3 })", Value::Num(1)),
+            ("find(l, fn(x){ x > 5 })", Value::Null),
+            (
+                "join(map(sort_by(rats, fn(r){ r.HP }), fn(r){ r.HP }), \",\")",
+                Value::str("2,5,9"),
+            ),
+            ("join(split(\"a b c\", \" \"), \"-\")", Value::str("a-b-c")),
+            ("upper(\"ab\") + lower(\"CD\")", Value::str("ABcd")),
+            ("sum(l.1..3)", Value::Num(3)),
+            ("sum(l.-2..)", Value::Num(3)),
+            ("sum(l.0..=1)", Value::Num(4)),
+            ("\"hello\".1..3", Value::str("el")),
+            ("l.-1", Value::Num(2)),
+            ("sum(l.(n - 2)..(n))", Value::Num(3)),
+            ("sum(l)", Value::Num(6)),
+            ("sum(values({doc:1, a:2}))", Value::Num(3)),
+        ];
+        for (s, v) in cases.iter() {
+            assert_eq!(&eval(&mut sc, s), v, "{}", s);
+        }
+    }
+}
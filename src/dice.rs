@@ -0,0 +1,291 @@
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::VecDeque;
+use std::fmt::{Display, Formatter};
+
+/// Limits rerolls on exploding dice, so a d1! cannot loop forever
+const MAX_EXPLODE: usize = 100;
+
+/// Most dice one term may roll, so a typo like 1000000000d6 is refused, not allocated
+pub const MAX_DICE: usize = 1000;
+
+/// Most sides a die may have, keeps faces and their sums well inside isize
+pub const MAX_SIDES: usize = 1_000_000;
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum DiceMod {
+    KeepHigh(usize),
+    KeepLow(usize),
+    DropHigh(usize),
+    DropLow(usize),
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct Dice {
+    pub count: usize,
+    pub sides: usize,
+    pub md: Option<DiceMod>,
+    pub explode: bool,
+}
+
+/// Written as the player would type it, so a single die is "d20" not "1d20"
+impl Display for Dice {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        if self.count != 1 {
+            write!(f, "{}", self.count)?;
+        }
+        write!(f, "d{}", self.sides)?;
+        if self.explode {
+            write!(f, "!")?;
+        }
+        match self.md {
+            Some(DiceMod::KeepHigh(n)) => write!(f, "kh{}", n),
+            Some(DiceMod::KeepLow(n)) => write!(f, "kl{}", n),
+            Some(DiceMod::DropHigh(n)) => write!(f, "dh{}", n),
+            Some(DiceMod::DropLow(n)) => write!(f, "dl{}", n),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The source of every die face in a Scope.
+/// Faces are logged as they are made, so a session can be replayed exactly
+/// either by reseeding or by feeding the log back in.
+#[derive(Debug)]
+pub struct DiceRng {
+    seed: u64,
+    rng: StdRng,
+    log: Vec<isize>,
+    replay: VecDeque<isize>,
+}
+
+impl DiceRng {
+    pub fn new(seed: u64) -> Self {
+        DiceRng {
+            seed,
+            rng: StdRng::seed_from_u64(seed),
+            log: Vec::new(),
+            replay: VecDeque::new(),
+        }
+    }
+
+    /// Kept within isize, so a script can read the seed back as a num
+    pub fn from_entropy() -> Self {
+        Self::new(rand::random::<u64>() >> 1)
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Restarts the stream, dropping anything still queued to replay.
+    /// The log keeps every face, so replaying it reaches the reseed with the same rolls behind it
+    pub fn reseed(&mut self, seed: u64) {
+        self.seed = seed;
+        self.rng = StdRng::seed_from_u64(seed);
+        self.replay.clear();
+    }
+
+    /// Queues faces to be used before any new ones are generated
+    pub fn replay<I: IntoIterator<Item = isize>>(&mut self, faces: I) {
+        self.replay.extend(faces);
+    }
+
+    pub fn log(&self) -> &[isize] {
+        &self.log
+    }
+
+    /// A replayed face that cannot be on this die means the replay no longer matches,
+    /// so the rest of it is dropped and the face is rolled instead
+    pub fn face(&mut self, sides: usize) -> isize {
+        let f = match self.replay.pop_front() {
+            Some(f) if f >= 1 && f <= sides as isize => f,
+            Some(_) => {
+                self.replay.clear();
+                self.rng.gen_range(1, sides + 1) as isize
+            }
+            None => self.rng.gen_range(1, sides + 1) as isize,
+        };
+        self.log.push(f);
+        f
+    }
+}
+
+/// One die, with any explosion rerolls, and whether it counted
+#[derive(PartialEq, Debug, Clone)]
+pub struct Die {
+    pub faces: Vec<isize>,
+    pub kept: bool,
+}
+
+impl Die {
+    pub fn total(&self) -> isize {
+        self.faces.iter().sum()
+    }
+}
+
+impl Display for Die {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        if !self.kept {
+            write!(f, "~")?;
+        }
+        match self.faces.len() {
+            1 => write!(f, "{}", self.faces[0]),
+            _ => {
+                let fs: Vec<String> = self.faces.iter().map(|n| n.to_string()).collect();
+                write!(f, "({})", fs.join("+"))
+            }
+        }
+    }
+}
+
+/// The record of a single dice roll, dice appear in the order they were rolled
+#[derive(PartialEq, Debug, Clone)]
+pub struct Roll {
+    pub dice: Dice,
+    pub dies: Vec<Die>,
+}
+
+impl Roll {
+    pub fn total(&self) -> isize {
+        self.dies.iter().filter(|d| d.kept).map(|d| d.total()).sum()
+    }
+}
+
+/// Prints as [4,~1,6], with dropped dice marked by '~'
+impl Display for Roll {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        let ds: Vec<String> = self.dies.iter().map(|d| d.to_string()).collect();
+        write!(f, "[{}]", ds.join(","))
+    }
+}
+
+impl Dice {
+    pub fn new(count: usize, sides: usize) -> Self {
+        Dice {
+            count,
+            sides,
+            md: None,
+            explode: false,
+        }
+    }
+
+    fn roll_one(&self, rng: &mut DiceRng) -> Die {
+        let mut faces = Vec::new();
+        for _ in 0..MAX_EXPLODE {
+            let face = rng.face(self.sides);
+            faces.push(face);
+            if !self.explode || face != self.sides as isize {
+                break;
+            }
+        }
+        Die { faces, kept: true }
+    }
+
+    pub fn roll(&self, rng: &mut DiceRng) -> Roll {
+        let mut dies: Vec<Die> = (0..self.count).map(|_| self.roll_one(rng)).collect();
+        //positions in ascending order of total, ties keep roll order
+        let mut order: Vec<usize> = (0..dies.len()).collect();
+        order.sort_by_key(|&i| dies[i].total());
+        let n = order.len();
+        let dropped = match self.md {
+            None => &order[..0],
+            Some(DiceMod::KeepHigh(k)) => &order[..n - k.min(n)],
+            Some(DiceMod::KeepLow(k)) => &order[k.min(n)..],
+            Some(DiceMod::DropHigh(k)) => &order[n - k.min(n)..],
+            Some(DiceMod::DropLow(k)) => &order[..k.min(n)],
+        };
+        for &i in dropped {
+            dies[i].kept = false;
+        }
+        Roll { dice: *self, dies }
+    }
+}
+
+#[cfg(test)]
+mod test_dice {
+    use super::*;
+
+    #[test]
+    fn test_dice_ranges() {
+        let mut rng = DiceRng::new(3);
+        let d = Dice {
+            count: 4,
+            sides: 6,
+            md: Some(DiceMod::KeepHigh(3)),
+            explode: false,
+        };
+        for _ in 0..200 {
+            let r = d.roll(&mut rng);
+            assert_eq!(r.dies.len(), 4);
+            assert_eq!(r.dies.iter().filter(|d| !d.kept).count(), 1);
+            let t = r.total();
+            assert!(t >= 3 && t <= 18, "4d6kh3 gave {}", r);
+        }
+        let d = Dice::new(1, 20);
+        for _ in 0..200 {
+            let r = d.roll(&mut rng).total();
+            assert!(r >= 1 && r <= 20, "d20 gave {}", r);
+        }
+    }
+
+    #[test]
+    fn test_dice_replay() {
+        let d = Dice::new(3, 6);
+        let mut rng = DiceRng::new(12);
+        let first: Vec<Roll> = (0..10).map(|_| d.roll(&mut rng)).collect();
+
+        rng.reseed(12);
+        let again: Vec<Roll> = (0..10).map(|_| d.roll(&mut rng)).collect();
+        assert_eq!(first, again);
+
+        let mut rp = DiceRng::new(99);
+        rp.replay(rng.log().to_vec());
+        let replayed: Vec<Roll> = (0..10).map(|_| d.roll(&mut rp)).collect();
+        assert_eq!(first, replayed);
+    }
+
+    #[test]
+    fn test_dice_display() {
+        assert_eq!(Dice::new(1, 20).to_string(), "d20");
+        let d = Dice {
+            count: 4,
+            sides: 6,
+            md: Some(DiceMod::KeepHigh(3)),
+            explode: true,
+        };
+        assert_eq!(d.to_string(), "4d6!kh3");
+    }
+
+    #[test]
+    fn test_big_dice() {
+        let mut rng = DiceRng::new(5);
+        let d = Dice {
+            count: MAX_DICE,
+            sides: MAX_SIDES,
+            md: None,
+            explode: true,
+        };
+        let t = d.roll(&mut rng).total();
+        assert!(t >= MAX_DICE as isize, "big roll gave {}", t);
+    }
+
+    #[test]
+    fn test_replay_reset() {
+        let d = Dice::new(3, 6);
+        let mut fresh = DiceRng::new(4);
+        let want = d.roll(&mut fresh);
+
+        //a reseed drops what was queued
+        let mut rng = DiceRng::new(12);
+        rng.replay(vec![1, 1, 1]);
+        rng.reseed(4);
+        assert_eq!(d.roll(&mut rng), want);
+
+        //faces that don't fit the die end the replay
+        let mut rng = DiceRng::new(4);
+        rng.replay(vec![9, 2, 2]);
+        assert_eq!(d.roll(&mut rng), want);
+        assert_eq!(rng.log(), fresh.log());
+    }
+}
@@ -1,5 +1,7 @@
 //use crate::error::ActionError;
+use crate::proto::Proto;
 use crate::value::Value;
+use std::collections::BTreeMap;
 
 #[derive(Debug)]
 pub struct GenData {
@@ -34,6 +36,11 @@ impl GenData {
         self.clone_weak().to_strong(gm)
     }
 
+    /// Where in the store this points, whether or not it is still live
+    pub fn slot(&self) -> usize {
+        self.pos
+    }
+
     pub fn clone_ig(&self) -> Self {
         GenData {
             pos: self.pos,
@@ -50,10 +57,21 @@ pub struct StoreItem {
     rc: u64,
 }
 
+/// A snapshot of the store, see GenManager::stats
+#[derive(Debug, Clone, PartialEq)]
+pub struct GenStats {
+    pub slots: usize,
+    pub live: usize,
+    pub free: usize,
+    pub churn: u64, //times a slot has been reused
+    pub allocs: usize,
+}
+
 #[derive(Debug)]
 pub struct GenManager {
     drops: Vec<usize>,
     items: Vec<StoreItem>,
+    allocs: usize, //since the last collect
 }
 
 impl GenManager {
@@ -61,6 +79,7 @@ impl GenManager {
         GenManager {
             drops: Vec::new(),
             items: Vec::new(),
+            allocs: 0,
         }
     }
 
@@ -82,7 +101,171 @@ impl GenManager {
         self.items[gd.pos].val.as_mut()
     }
 
+    /// How many values are currently stored
+    pub fn live(&self) -> usize {
+        self.items.iter().filter(|i| i.val.is_some()).count()
+    }
+
+    pub fn allocs(&self) -> usize {
+        self.allocs
+    }
+
+    pub fn stats(&self) -> GenStats {
+        GenStats {
+            slots: self.items.len(),
+            live: self.live(),
+            free: self.drops.len(),
+            churn: self.items.iter().map(|i| i.gen).sum(),
+            allocs: self.allocs,
+        }
+    }
+
+    /// Live values that cannot be reached from the roots, these are leaks
+    pub fn unreachable<'a, I: Iterator<Item = &'a GenData>>(&self, roots: I) -> usize {
+        let marked = self.mark(roots);
+        (0..self.items.len())
+            .filter(|p| !marked[*p] && self.items[*p].val.is_some())
+            .count()
+    }
+
+    pub fn is_live(&self, g: &GenData) -> bool {
+        match self.items.get(g.pos) {
+            Some(ea) => ea.gen == g.gen && ea.val.is_some(),
+            None => false,
+        }
+    }
+
+    /// Mark and sweep from the roots, this frees reference cycles that counting can't.
+    /// Anything held outside the manager and the roots will be freed too,
+    /// so only call this between top level actions
+    pub fn collect<'a, I: Iterator<Item = &'a GenData>>(&mut self, roots: I) -> usize {
+        let marked = self.mark(roots);
+        let mut freed = 0;
+        for p in 0..self.items.len() {
+            if marked[p] {
+                continue;
+            }
+            if let Some(v) = self.items[p].val.take() {
+                freed += 1;
+                self.items[p].rc = 0;
+                self.drops.push(p);
+                //Survivors lose the counts the dead held on them
+                for c in v.gen_drop() {
+                    if c.strong && marked[c.pos] && self.items[c.pos].gen == c.gen {
+                        let ea = &mut self.items[c.pos];
+                        ea.rc = ea.rc.saturating_sub(1);
+                    }
+                }
+            }
+        }
+        self.allocs = 0;
+        freed
+    }
+
+    fn mark<'a, I: Iterator<Item = &'a GenData>>(&self, roots: I) -> Vec<bool> {
+        let mut marked = vec![false; self.items.len()];
+        let mut stack: Vec<usize> = roots.filter(|g| self.is_live(g)).map(|g| g.pos).collect();
+        while let Some(p) = stack.pop() {
+            if marked[p] {
+                continue;
+            }
+            marked[p] = true;
+            if let Some(v) = &self.items[p].val {
+                for c in v.children() {
+                    if self.is_live(c) && !marked[c.pos] {
+                        stack.push(c.pos);
+                    }
+                }
+            }
+        }
+        marked
+    }
+
+    /// Copies everything below g into new slots, so the copy shares no state.
+    /// What references point to, such as protos, is shared unless refs is set.
+    /// Values reached twice are copied once, so cycles stay cycles
+    pub fn deep_clone(&mut self, g: &GenData, refs: bool) -> Option<GenData> {
+        self.deep_clone_with(g, refs, &|_| None)
+    }
+
+    /// As deep_clone, and weak references into the copied part then point at the copy.
+    /// relink may give a new target for each copied link, as links are paths not slots
+    pub fn deep_clone_with(
+        &mut self,
+        g: &GenData,
+        refs: bool,
+        relink: &dyn Fn(&Proto) -> Option<Proto>,
+    ) -> Option<GenData> {
+        let mut done = BTreeMap::new();
+        let res = self.clone_into(g, refs, &mut done)?;
+        for c in done.values() {
+            let nv = match self.get(c) {
+                Some(Value::Weak(w)) if self.is_live(w) => match done.get(&w.pos) {
+                    Some(nw) => Value::Weak(nw.clone_weak()),
+                    None => continue,
+                },
+                Some(Value::Link(p)) => match relink(p) {
+                    Some(np) => Value::Link(np),
+                    None => continue,
+                },
+                _ => continue,
+            };
+            if let Some(slot) = self.get_mut(c) {
+                *slot = nv;
+            }
+        }
+        Some(res)
+    }
+
+    fn clone_into(
+        &mut self,
+        g: &GenData,
+        refs: bool,
+        done: &mut BTreeMap<usize, GenData>,
+    ) -> Option<GenData> {
+        if !self.is_live(g) {
+            return None;
+        }
+        if let Some(c) = done.get(&g.pos) {
+            return Some(c.clone_strong(self));
+        }
+        let v = self.get(g)?.clone_weak();
+        //Take the slot first, so children can point back at it
+        let res = self.push(Value::Null);
+        done.insert(g.pos, res.clone_weak());
+        let nv = match v {
+            Value::List(l) => {
+                Value::List(l.iter().map(|c| self.clone_child(c, refs, done)).collect())
+            }
+            Value::Map(m) => Value::Map(
+                m.into_iter()
+                    .map(|(k, c)| (k, self.clone_child(&c, refs, done)))
+                    .collect(),
+            ),
+            Value::Ref(c) if refs => Value::Ref(self.clone_child(&c, refs, done)),
+            v => v.to_strong(self),
+        };
+        if let Some(slot) = self.get_mut(&res) {
+            *slot = nv;
+        }
+        Some(res)
+    }
+
+    /// A freed child stays a dead (weak) handle in the copy
+    fn clone_child(
+        &mut self,
+        c: &GenData,
+        refs: bool,
+        done: &mut BTreeMap<usize, GenData>,
+    ) -> GenData {
+        match self.clone_into(c, refs, done) {
+            Some(n) => n,
+            None => c.clone_weak(),
+        }
+    }
+
     pub fn push(&mut self, v: Value) -> GenData {
+        self.allocs += 1;
         if let Some(loc) = self.drops.pop() {
             let ea = &mut self.items[loc];
             ea.val = Some(v);
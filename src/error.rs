@@ -1,5 +1,8 @@
+use crate::proto::Proto;
+use crate::value::Value;
 use failure_derive::*;
 use gobble::err::ParseError;
+use std::collections::BTreeMap;
 
 #[derive(Debug, Fail, PartialEq)]
 #[fail(display = "Parse Error: line {} :{}", line, mess)]
@@ -32,23 +35,225 @@ impl LineError {
     }
 }
 
+/// Where a statement came from, lines and cols count from 0 as in gobble
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Span {
+    pub src: usize, //index of the Source in the Scope
+    pub line: usize,
+    pub col: usize,
+    pub end_line: usize,
+    pub end_col: usize,
+}
+
+/// A file or REPL input, kept so errors can show the offending line
+#[derive(Debug)]
+pub struct Source {
+    pub name: String,
+    pub text: String,
+}
+
+impl Source {
+    /// The numbered source line with carets under cols [col,end_col)
+    pub fn point(&self, line: usize, col: usize, end_col: usize) -> String {
+        let l_str = self.text.lines().nth(line).unwrap_or("");
+        let l_num = (line + 1).to_string();
+        let pad: String = l_num.chars().map(|_| ' ').collect();
+        let end_col = end_col.min(l_str.chars().count()).max(col + 1);
+        let mut res = format!(" {} --> {}:{}:{}\n", pad, self.name, line + 1, col + 1);
+        res.push_str(&format!(" {} |\n", pad));
+        res.push_str(&format!(" {} | {}\n", l_num, l_str));
+        res.push_str(&format!(" {} | ", pad));
+        //tabs are kept so the carets line up however wide they show
+        let lead = l_str.chars().chain(std::iter::repeat(' ')).take(col);
+        res.extend(lead.map(|c| if c == '\t' { '\t' } else { ' ' }));
+        res.extend((col..end_col).map(|_| '^'));
+        res
+    }
+}
+
 #[derive(Debug, Fail, Clone, PartialEq)]
 #[fail(Display)]
 pub enum ActionError {
-    #[fail(display = "Parse Error: {}", 0)]
-    ParseErr(ParseError),
-    #[fail(display = "Action Error: {}", 0)]
-    DoingErr(String),
+    /// The index of the Source that failed to parse, and why
+    #[fail(display = "Parse Error: {}", 1)]
+    ParseErr(usize, ParseError),
+    /// The index and name of the Source, and what went wrong in it
+    #[fail(display = "Parse Errors in {}", 1)]
+    ParseErrs(usize, String, Vec<ParseError>),
+    #[fail(display = "Nothing at path: {}", 0)]
+    PathNotFound(Proto),
+    #[fail(display = "Cannot {} {} and {}", op, a, b)]
+    TypeMismatch {
+        op: &'static str,
+        a: &'static str,
+        b: &'static str,
+    },
+    #[fail(display = "Expected {}, got {}", expected, got)]
+    WrongType {
+        expected: &'static str,
+        got: &'static str,
+    },
+    #[fail(display = "Index {} is out of range for a list of {}", 0, 1)]
+    OutOfRange(String, usize),
+    #[fail(display = "Divide by zero")]
+    DivideByZero,
+    #[fail(display = "Number too big")]
+    Overflow,
+    #[fail(display = "Ranges hold at most {} nums", 0)]
+    RangeLimit(usize),
+    #[fail(display = "Dice can have 1 to {} sides, not {}", max, got)]
+    DiceSides { max: usize, got: isize },
+    #[fail(display = "Expected {} params, got {}", expected, got)]
+    Arity { expected: usize, got: usize },
+    #[fail(display = "Reference to freed value")]
+    FreedReference,
+    #[fail(display = "Cannot call a {}", 0)]
+    NotCallable(&'static str),
+    #[fail(display = "break or continue outside a loop")]
+    NotInLoop,
+    #[fail(display = "Loop ran more than {} times", 0)]
+    LoopLimit(usize),
+    #[fail(display = "Cannot use {} between values", 0)]
+    BadOperator(&'static str),
+    #[fail(display = "Cannot move {} inside itself", 0)]
+    MoveIntoSelf(Proto),
+    #[fail(display = "{} cannot link into itself", 0)]
+    LinkIntoSelf(Proto),
+    #[fail(display = "{} already exists", 0)]
+    AlreadyExists(Proto),
+    #[fail(display = "{} does not end in a name", 0)]
+    NotNamed(Proto),
+    #[fail(display = "Empty path")]
+    EmptyPath,
+    #[fail(display = "Nothing selected to pop")]
+    NothingSelected,
+    #[fail(display = "No bookmark called {}", 0)]
+    NoBookmark(String),
+    #[fail(display = "A loop takes 1 or 2 names, got {}", 0)]
+    LoopNames(usize),
+    #[fail(display = "Values nest more than {} deep to compare", 0)]
+    CompareDepth(usize),
+    #[fail(display = "Can spawn 1 to {} at once, not {}", max, got)]
+    SpawnCount { max: usize, got: isize },
+    #[fail(display = "Links go round in a cycle at {}", 0)]
+    LinkCycle(Proto),
+    #[fail(display = "IO Error: {}", 0)]
+    Io(String),
+    #[fail(display = "{}", 1)]
+    At(Span, Box<ActionError>),
 }
 
 impl ActionError {
-    pub fn new(s: &str) -> Self {
-        ActionError::DoingErr(s.to_string())
+    pub fn mismatch(op: &'static str, a: &Value, b: &Value) -> Self {
+        ActionError::TypeMismatch {
+            op,
+            a: a.type_name(),
+            b: b.type_name(),
+        }
+    }
+
+    pub fn wrong_type(expected: &'static str, got: &Value) -> Self {
+        ActionError::WrongType {
+            expected,
+            got: got.type_name(),
+        }
+    }
+
+    /// The error without any location wrapper, for matching on
+    pub fn kind(&self) -> &ActionError {
+        match self {
+            ActionError::At(_, e) => e.kind(),
+            e => e,
+        }
+    }
+
+    fn is_parse_errs(&self) -> bool {
+        match self {
+            ActionError::ParseErrs(_, _, _) => true,
+            _ => false,
+        }
+    }
+
+    /// Marks where the error happened, keeping the innermost location
+    pub fn at(self, sp: Span) -> Self {
+        match self {
+            ActionError::At(_, _) => self,
+            e => ActionError::At(sp, Box::new(e)),
+        }
+    }
+
+    /// The error with the source line it came from.
+    /// Sources are keyed by index, as old ones may have been dropped
+    pub fn render(&self, srcs: &BTreeMap<usize, Source>) -> String {
+        match self {
+            //A load that failed to parse points into its own file
+            ActionError::At(_, e) if e.is_parse_errs() => e.render(srcs),
+            ActionError::At(sp, e) => match srcs.get(&sp.src) {
+                Some(src) => {
+                    let end_col = match sp.end_line == sp.line {
+                        true => sp.end_col,
+                        false => usize::max_value(),
+                    };
+                    format!("{}\n{}", e, src.point(sp.line, sp.col, end_col))
+                }
+                None => self.to_string(),
+            },
+            ActionError::ParseErrs(src, _, errs) => match srcs.get(src) {
+                Some(s) => {
+                    let mut res = String::new();
+                    for pe in errs {
+                        res.push_str(&format!(
+                            "{}\n{}\n",
+                            ActionError::ParseErr(*src, pe.clone()),
+                            s.point(pe.line, pe.col, pe.col + 1)
+                        ));
+                    }
+                    res.push_str(&format!("{} bad statements in {}", errs.len(), s.name));
+                    res
+                }
+                None => self.to_string(),
+            },
+            ActionError::ParseErr(src, pe) => match srcs.get(src) {
+                Some(src) => format!("{}\n{}", self, src.point(pe.line, pe.col, pe.col + 1)),
+                None => self.to_string(),
+            },
+            e => e.to_string(),
+        }
     }
 }
 
-impl From<ParseError> for ActionError {
-    fn from(p: ParseError) -> ActionError {
-        ActionError::ParseErr(p)
+#[cfg(test)]
+mod test_error {
+    use super::*;
+    #[test]
+    fn test_render_caret() {
+        let src = Source {
+            name: "t.dd".to_string(),
+            text: "a = 1\nb = c.d\n".to_string(),
+        };
+        let sp = Span {
+            src: 0,
+            line: 1,
+            col: 0,
+            end_line: 1,
+            end_col: 7,
+        };
+        let mut srcs = BTreeMap::new();
+        srcs.insert(0, src);
+        let r = ActionError::PathNotFound(Proto::str("c.d"))
+            .at(sp)
+            .render(&srcs);
+        assert!(r.contains("t.dd:2:1"), "{}", r);
+        assert!(r.contains(" 2 | b = c.d\n"), "{}", r);
+        assert!(r.ends_with("   | ^^^^^^^"), "{}", r);
+    }
+
+    #[test]
+    fn test_caret_tabs() {
+        let src = Source {
+            name: "t.dd".to_string(),
+            text: "\t\tb = c\n".to_string(),
+        };
+        assert!(src.point(0, 6, 7).ends_with("| \t\t    ^"));
     }
 }
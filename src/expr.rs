@@ -1,11 +1,15 @@
-use crate::action::{AcResult, AcReturn, Action};
-use crate::error::ActionError;
+use crate::action::{outside_loop, AcResult, AcReturn, Action};
+use crate::api_std;
+use crate::dice::{Dice, Roll};
+use crate::error::{ActionError, Span};
 //use crate::prev_iter::Backer;
 //use crate::prev_iter::LineCounter;
 use crate::proto::{Proto, ProtoNode};
-use crate::scope::Scope;
+use crate::scope::{num_range, Scope};
 use crate::value::Value;
-use gobble::err::ECode;
+use gobble::err::{ECode, ParseError};
+use gobble::Parser;
+use std::cmp::Ordering;
 use std::collections::BTreeMap;
 use std::str::FromStr;
 
@@ -25,8 +29,21 @@ pub enum Op {
     Mul,
     Greater,
     Less,
+    GreaterEq,
+    LessEq,
     Dot,
     Equal,
+    NotEqual,
+    Mod,
+    And,
+    Or,
+}
+
+impl FromStr for Expr {
+    type Err = ParseError;
+    fn from_str(s: &str) -> Result<Self, ParseError> {
+        crate::nomp::full_expr().parse_s(s)
+    }
 }
 
 impl FromStr for Op {
@@ -40,25 +57,32 @@ impl FromStr for Op {
             "*" => Mul,
             ">" => Greater,
             "<" => Less,
+            ">=" => GreaterEq,
+            "<=" => LessEq,
             "." => Dot,
             "==" => Equal,
+            "!=" => NotEqual,
+            "%" => Mod,
+            "&&" => And,
+            "||" => Or,
             _ => return Err(ECode::Never("not a legit operator")),
         })
     }
 }
 
 impl Op {
-    pub fn rank(&self) -> i32 {
+    /// Binding power, higher binds tighter. All operators are left associative,
+    /// so "10 - 2 - 3" is "(10 - 2) - 3"
+    pub fn prec(&self) -> u8 {
         use Op::*;
         match self {
-            Dot => 0,
-            Add => 10,
-            Sub => 9,
-            Mul => 8,
-            Div => 7,
-            Greater => 6,
-            Less => 5,
-            Equal => 4,
+            Or => 1,
+            And => 2,
+            Equal | NotEqual => 3,
+            Greater | GreaterEq | Less | LessEq => 4,
+            Add | Sub => 5,
+            Mul | Div | Mod => 6,
+            Dot => 7,
         }
     }
 
@@ -72,20 +96,51 @@ impl Op {
             Div => "/",
             Greater => ">",
             Less => "<",
+            GreaterEq => ">=",
+            LessEq => "<=",
             Equal => "==",
+            NotEqual => "!=",
+            Mod => "%",
+            And => "&&",
+            Or => "||",
         }
     }
 
     pub fn eval(&self, a: &Expr, b: &Expr, sc: &mut Scope) -> Result<Value, ActionError> {
         match self {
-            Op::Add => a.eval(sc)?.try_add(b.eval(sc)?, sc.gm_mut()),
-            Op::Sub => a.eval(sc)?.try_sub(b.eval(sc)?),
-            Op::Mul => a.eval(sc)?.try_mul(b.eval(sc)?),
-            Op::Div => a.eval(sc)?.try_div(b.eval(sc)?),
-            Op::Greater => Ok(Value::Bool(a.eval(sc)? > b.eval(sc)?)),
-            Op::Less => Ok(Value::Bool(a.eval(sc)? < b.eval(sc)?)),
-            Op::Equal => Ok(Value::Bool(a.eval(sc)? == b.eval(sc)?)),
-            Op::Dot => Err(ActionError::new("Could not appy .= operation")),
+            Op::Dot => return Err(ActionError::BadOperator(".")),
+            //Logic ops short circuit, so b is only evaluated if needed
+            Op::And => return Ok(Value::Bool(a.eval_bool(sc)? && b.eval_bool(sc)?)),
+            Op::Or => return Ok(Value::Bool(a.eval_bool(sc)? || b.eval_bool(sc)?)),
+            _ => {}
+        }
+        let av = a.eval(sc)?;
+        let av = sc.deref_scalar(av);
+        let bv = b.eval(sc)?;
+        let bv = sc.deref_scalar(bv);
+        let ord = |o: Ordering| match self {
+            Op::Greater => o == Ordering::Greater,
+            Op::Less => o == Ordering::Less,
+            Op::GreaterEq => o != Ordering::Less,
+            _ => o != Ordering::Greater,
+        };
+        match self {
+            Op::Greater | Op::Less | Op::GreaterEq | Op::LessEq | Op::Equal | Op::NotEqual => {
+                let res = match self {
+                    Op::Equal => av.deep_eq(&bv, sc.gm()),
+                    Op::NotEqual => av.deep_eq(&bv, sc.gm()).map(|b| !b),
+                    _ => av.deep_cmp(&bv, sc.gm()).map(ord),
+                };
+                sc.gm_mut().drop(av);
+                sc.gm_mut().drop(bv);
+                res.map(|b| Value::Bool(b))
+            }
+            Op::Add => av.try_add(bv, sc.gm_mut()),
+            Op::Sub => av.try_sub(bv, sc.gm_mut()),
+            Op::Mul => av.try_mul(bv),
+            Op::Div => av.try_div(bv),
+            Op::Mod => av.try_mod(bv),
+            Op::Dot | Op::And | Op::Or => unreachable!(),
         }
     }
 }
@@ -94,26 +149,71 @@ impl Op {
 pub struct MapItem {
     pub k: String,
     pub v: Expr,
+    /// From `##` comments above the field, kept in the map as a Doc node,
+    /// which len, keys, loops and comparisons all pass over
+    pub doc: Option<String>,
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum Pattern {
+    Any,
+    Num(isize),
+    Str(String),
+    Bool(bool),
+    Range(isize, isize, bool), //inclusive
+}
+
+impl Pattern {
+    pub fn matches(&self, v: &Value) -> bool {
+        match (self, v) {
+            (Pattern::Any, _) => true,
+            (Pattern::Num(a), Value::Num(b)) => a == b,
+            (Pattern::Str(a), Value::Str(b)) => a == b,
+            (Pattern::Bool(a), Value::Bool(b)) => a == b,
+            (Pattern::Range(a, b, inc), Value::Num(n)) => a <= n && (n < b || (*inc && n == b)),
+            _ => false,
+        }
+    }
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct MatchArm {
+    pub pats: Vec<Pattern>,
+    pub body: Vec<Action>,
 }
 
 #[derive(PartialEq, Debug, Clone)]
 pub enum Expr {
+    Null,
     Bool(bool),
     Num(isize),
+    Dice(Dice),
     Str(String),
     Ident(String),
     Oper(Op, Box<Expr>, Box<Expr>),
     Bracket(Box<Expr>),
     Neg(Box<Expr>),
+    Not(Box<Expr>),
     DotStart(Box<Expr>),
     Rooted(Box<Expr>),
     Deref(Box<Expr>),
+    /// A new map inheriting from the one at the path
+    New(Box<Expr>),
+    /// A reference that does not keep its target alive
+    Weak(Box<Expr>),
     List(Vec<Expr>),
     Map(Vec<MapItem>),
     If(Box<Expr>, Vec<Action>, Vec<Action>),
+    Match(Box<Expr>, Vec<MatchArm>),
+    Range(Box<Expr>, Box<Expr>, bool), //inclusive
+    Slice(Box<Expr>, Box<Expr>, Option<Box<Expr>>, bool), //inclusive
+    For(Vec<String>, Box<Expr>, Vec<Action>),
+    While(Box<Expr>, Vec<Action>),
     Call(Box<Expr>, Vec<Expr>),
     ExprDef(Vec<String>, Box<Expr>),
     FuncDef(Vec<String>, Vec<Action>),
+    /// Where the expression was found, so errors inside it point at it
+    At(Span, Box<Expr>),
 }
 
 impl Expr {
@@ -121,22 +221,81 @@ impl Expr {
         Expr::Neg(Box::new(e))
     }
 
+    /// Points the spans of all actions inside the expression at the given source
+    pub fn set_src(&mut self, src: usize) {
+        use Expr::*;
+        let blk = |b: &mut Vec<Action>| b.iter_mut().for_each(|a| a.set_src(src));
+        match self {
+            Null | Bool(_) | Num(_) | Dice(_) | Str(_) | Ident(_) => {}
+            Bracket(a) | Neg(a) | Not(a) | DotStart(a) | Rooted(a) | Deref(a) | New(a)
+            | Weak(a) => a.set_src(src),
+            Oper(_, a, b) | Range(a, b, _) => {
+                a.set_src(src);
+                b.set_src(src);
+            }
+            Slice(e, a, b, _) => {
+                e.set_src(src);
+                a.set_src(src);
+                if let Some(b) = b {
+                    b.set_src(src);
+                }
+            }
+            List(l) => l.iter_mut().for_each(|e| e.set_src(src)),
+            Map(l) => l.iter_mut().for_each(|m| m.v.set_src(src)),
+            If(c, l, r) => {
+                c.set_src(src);
+                blk(l);
+                blk(r);
+            }
+            Match(e, arms) => {
+                e.set_src(src);
+                arms.iter_mut().for_each(|a| blk(&mut a.body));
+            }
+            For(_, e, b) | While(e, b) => {
+                e.set_src(src);
+                blk(b);
+            }
+            Call(f, args) => {
+                f.set_src(src);
+                args.iter_mut().for_each(|e| e.set_src(src));
+            }
+            ExprDef(_, e) => e.set_src(src),
+            FuncDef(_, b) => blk(b),
+            At(sp, e) => {
+                sp.src = src;
+                e.set_src(src);
+            }
+        }
+    }
+
+    pub fn is_path(&self) -> bool {
+        use Expr::*;
+        match self {
+            Ident(_) | DotStart(_) | Rooted(_) | Oper(Op::Dot, _, _) => true,
+            At(_, e) => e.is_path(),
+            _ => false,
+        }
+    }
+
     pub fn eval_path(&self, sc: &mut Scope) -> Result<Proto, ActionError> {
         use Expr::*;
         Ok(match self {
-            Num(n) => Proto::num(*n as usize),
+            Num(n) => Proto::index(*n),
             Str(s) => Proto::str(s),
             Ident(s) => Proto::str(s),
             Rooted(e) => e.eval_path(sc)?.rooted(),
-            Deref(e) => Proto::dr().extend_new(e.eval_path(sc)?.pp()),
+            At(sp, e) => e.eval_path(sc).map_err(|er| er.at(*sp))?,
+            //a write to $a goes where a write to a would, through the reference
+            Deref(e) => e.eval_path(sc)?,
             DotStart(e) => e.eval_path(sc)?.dot(),
             Oper(Op::Dot, a, b) => a.eval_path(sc)?.extend_new(b.eval_path(sc)?.pp()),
             ot => match ot.eval(sc)? {
-                Value::Num(n) => Proto::num(n as usize),
+                Value::Num(n) => Proto::index(n),
                 Value::Str(s) => Proto::str(&s),
                 ov => {
+                    let e = ActionError::wrong_type("num or str path", &ov);
                     sc.gm_mut().drop(ov);
-                    return Err(ActionError::new("Could not treat as proto"));
+                    return Err(e);
                 }
             },
         })
@@ -146,16 +305,57 @@ impl Expr {
         //println!("eval {}",self.print());
         use Expr::*;
         Ok(match self {
+            Null => Value::Null,
             Bool(b) => Value::Bool(*b),
             Num(n) => Value::Num(*n),
+            Dice(d) => {
+                let r = d.roll(sc.rng_mut());
+                let n = r.total();
+                sc.push_roll(r);
+                Value::Num(n)
+            }
             Str(s) => Value::Str(s.clone()),
 
             Bracket(a) => a.eval(sc)?,
-            Neg(a) => a.eval(sc)?.try_neg()?,
+            At(sp, e) => e.eval(sc).map_err(|er| er.at(*sp))?,
+            Neg(a) => {
+                let v = a.eval(sc)?;
+                sc.deref_scalar(v).try_neg()?
+            }
+            Not(a) => Value::Bool(!a.eval_bool(sc)?),
             Oper(Op::Dot, _, _) | Ident(_) | DotStart(_) | Rooted(_) => {
                 let proto = self.eval_path(sc)?;
-                let v = sc.get(&proto).ok_or(ActionError::new("Nothing at path"))?;
-                v.clone_weak().to_strong(sc.gm_mut())
+                let v = sc.lookup(&proto)?.1;
+                match v {
+                    //An expr without params is a computed value
+                    Value::ExprDef(p, ex) if p.len() == 0 => {
+                        let f = Value::ExprDef(Vec::new(), ex.clone());
+                        let owner = sc.owner_of(&proto);
+                        sc.call_func(f, owner, Vec::new())?
+                    }
+                    v => v.clone_weak().to_strong(sc.gm_mut()),
+                }
+            }
+            Deref(e) => {
+                let proto = e.eval_path(sc)?;
+                let gd = sc.lookup(&proto)?.0;
+                Value::Ref(gd.clone_strong(sc.gm_mut()))
+            }
+            New(e) => {
+                let proto = e.eval_path(sc)?;
+                let gd = sc.lookup(&proto)?.0;
+                match sc.gm().get(&gd) {
+                    Some(Value::Map(_)) => {}
+                    Some(v) => return Err(ActionError::wrong_type("map", v)),
+                    None => return Err(ActionError::FreedReference),
+                }
+                let gd = gd.clone_strong(sc.gm_mut());
+                sc.instance(gd)
+            }
+            Weak(e) => {
+                let proto = e.eval_path(sc)?;
+                let gd = sc.lookup(&proto)?.0;
+                Value::Weak(gd)
             }
             Oper(o, a, b) => o.eval(a, b, sc)?,
             List(ref l) => {
@@ -171,6 +371,9 @@ impl Expr {
                 for e in l {
                     let v = e.v.eval(sc)?;
                     res.insert(ProtoNode::str(&e.k), sc.push_mem(v));
+                    if let Some(d) = &e.doc {
+                        res.insert(ProtoNode::Doc(e.k.clone()), sc.push_mem(Value::str(d)));
+                    }
                 }
                 Value::Map(res)
             }
@@ -179,11 +382,43 @@ impl Expr {
                 let op = match v {
                     Value::Bool(true) => l_block,
                     Value::Bool(false) => r_block,
-                    _ => return Err(ActionError::new("If only works on bool expr")),
+                    v => return Err(ActionError::wrong_type("bool", &v)),
+                };
+                outside_loop(sc.on_wrap(|sc2| sc2.do_actions(op)))?
+            }
+            Slice(e, a, b, inc) => {
+                let a = a.eval_num(sc)?;
+                let b = match b {
+                    Some(b) => Some(b.eval_num(sc)?),
+                    None => None,
                 };
-                sc.on_wrap(|sc2| sc2.do_actions(op)).map(|v| v.1)?
+                let v = e.eval(sc)?;
+                api_std::slice(sc, v, a, b, *inc)?
+            }
+            Range(a, b, inc) => {
+                let (a, b) = (a.eval_num(sc)?, b.eval_num(sc)?);
+                let mut res = Vec::new();
+                for n in num_range(a, b, *inc)? {
+                    res.push(sc.push_mem(Value::Num(n)));
+                }
+                Value::List(res)
+            }
+            For(_, _, _) | While(_, _) | Match(_, _) => self.eval_action(sc)?.1,
+            ExprDef(p, e) => Value::ExprDef(p.clone(), (**e).clone()),
+            FuncDef(p, acs) => Value::FuncDef(p.clone(), acs.clone()),
+            Call(f, args) => {
+                let mut params = Vec::new();
+                for a in args {
+                    params.push(a.eval(sc)?);
+                }
+                if f.is_path() {
+                    let proto = f.eval_path(sc)?;
+                    sc.call_path(&proto, params)?
+                } else {
+                    let fv = f.eval(sc)?;
+                    sc.call_func(fv, None, params)?
+                }
             }
-            _ => unimplemented!(),
         })
     }
 
@@ -194,55 +429,389 @@ impl Expr {
                 let op = match v {
                     Value::Bool(true) => l_block,
                     Value::Bool(false) => r_block,
-                    _ => return Err(ActionError::new("If only works on bool expr")),
+                    v => return Err(ActionError::wrong_type("bool", &v)),
                 };
                 sc.on_wrap(|sc2| sc2.do_actions(op))
             }
+            Self::For(names, it, block) => {
+                let items = match &**it {
+                    Self::Range(a, b, inc) => {
+                        let (a, b) = (a.eval_num(sc)?, b.eval_num(sc)?);
+                        num_range(a, b, *inc)?
+                            .map(|n| (Value::Num(n), Value::Num(n)))
+                            .collect()
+                    }
+                    e => {
+                        let v = e.eval(sc)?;
+                        sc.loop_items(v)?
+                    }
+                };
+                sc.on_wrap(|sc2| sc2.run_for(names, items, block))
+            }
+            Self::Match(ex, arms) => {
+                let v = ex.eval(sc)?;
+                let v = sc.deref_scalar(v);
+                let arm = arms.iter().find(|a| a.pats.iter().any(|p| p.matches(&v)));
+                sc.gm_mut().drop(v);
+                match arm {
+                    Some(a) => sc.on_wrap(|sc2| sc2.do_actions(&a.body)),
+                    None => Ok((AcReturn::No, Value::Null)),
+                }
+            }
+            Self::While(cond, block) => sc.on_wrap(|sc2| sc2.run_while(cond, block)),
+            Self::At(sp, e) => e.eval_action(sc).map_err(|er| er.at(*sp)),
             _ => self.eval(sc).map(|v| (AcReturn::No, v)),
         }
     }
-    pub fn add_left(self, lf: Expr, op: Op) -> Self {
-        match self {
-            Expr::Oper(sop, sa, sb) => {
-                if sop.rank() >= op.rank() {
-                    Expr::Oper(sop, Box::new(sa.add_left(lf, op)), sb)
-                } else {
-                    Expr::Oper(op, Box::new(lf), Box::new(Expr::Oper(sop, sa, sb)))
-                }
+
+    pub fn eval_bool(&self, sc: &mut Scope) -> Result<bool, ActionError> {
+        let v = self.eval(sc)?;
+        match sc.deref_scalar(v) {
+            Value::Bool(b) => Ok(b),
+            v => {
+                let e = ActionError::wrong_type("bool", &v);
+                sc.gm_mut().drop(v);
+                Err(e)
+            }
+        }
+    }
+
+    pub fn eval_num(&self, sc: &mut Scope) -> Result<isize, ActionError> {
+        let v = self.eval(sc)?;
+        match sc.deref_scalar(v) {
+            Value::Num(n) => Ok(n),
+            v => {
+                let e = ActionError::wrong_type("num", &v);
+                sc.gm_mut().drop(v);
+                Err(e)
             }
-            e => Expr::Oper(op, Box::new(lf), Box::new(e)),
         }
     }
 
     pub fn print(&self) -> String {
+        self.print_rolls(&mut [].iter())
+    }
+
+    /// Prints the expression with each dice term replaced by the next roll made for it.
+    /// Anything else prints as its source text, blocks are shortened to "{..}"
+    pub fn print_rolls(&self, rolls: &mut std::slice::Iter<Roll>) -> String {
         use Expr::*;
         match self {
+            Null => "null".to_string(),
+            Bool(b) => b.to_string(),
             Num(n) => n.to_string(),
+            Dice(d) => match rolls.next() {
+                Some(r) => r.to_string(),
+                None => d.to_string(),
+            },
             Str(s) => format!("\"{}\"", s),
-            Oper(o, a, b) => format!("{}{}{})", a.print(), o.to_str(), b.print()),
-            Neg(a) => format!("-{}", a.print()),
-            Bracket(b) => format!("({})", b.print()),
-            e => format!("{:?}", e),
+            Ident(s) => s.clone(),
+            Oper(o, a, b) => format!(
+                "{}{}{}",
+                a.print_rolls(rolls),
+                o.to_str(),
+                b.print_rolls(rolls)
+            ),
+            Neg(a) => format!("-{}", a.print_rolls(rolls)),
+            Not(a) => format!("!{}", a.print_rolls(rolls)),
+            Bracket(b) => format!("({})", b.print_rolls(rolls)),
+            DotStart(a) => format!(".{}", a.print_rolls(rolls)),
+            Rooted(a) => format!(":{}", a.print_rolls(rolls)),
+            Deref(a) => format!("${}", a.print_rolls(rolls)),
+            Weak(a) => format!("&{}", a.print_rolls(rolls)),
+            New(a) => format!("new {}", a.print_rolls(rolls)),
+            List(l) => format!("[{}]", print_list(l, rolls)),
+            Map(m) => {
+                let items: Vec<String> = m
+                    .iter()
+                    .map(|it| format!("{}:{}", it.k, it.v.print_rolls(rolls)))
+                    .collect();
+                format!("{{{}}}", items.join(","))
+            }
+            Range(a, b, inc) => format!(
+                "{}..{}{}",
+                a.print_rolls(rolls),
+                if *inc { "=" } else { "" },
+                b.print_rolls(rolls)
+            ),
+            Slice(a, from, to, inc) => format!(
+                "{}.{}..{}{}",
+                a.print_rolls(rolls),
+                from.print_rolls(rolls),
+                if *inc { "=" } else { "" },
+                match to {
+                    Some(t) => t.print_rolls(rolls),
+                    None => String::new(),
+                }
+            ),
+            Call(f, args) => format!("{}({})", f.print_rolls(rolls), print_list(args, rolls)),
+            If(c, _, _) => format!("if {} {{..}}", c.print_rolls(rolls)),
+            Match(e, _) => format!("match {} {{..}}", e.print_rolls(rolls)),
+            For(names, e, _) => {
+                format!("for {} in {} {{..}}", names.join(","), e.print_rolls(rolls))
+            }
+            While(c, _) => format!("while {} {{..}}", c.print_rolls(rolls)),
+            ExprDef(ps, e) => format!("expr({})({})", ps.join(","), e.print_rolls(rolls)),
+            FuncDef(ps, _) => format!("fn({}){{..}}", ps.join(",")),
+            At(_, e) => e.print_rolls(rolls),
         }
     }
+
+    pub fn count_dice(&self) -> usize {
+        use Expr::*;
+        match self {
+            Dice(_) => 1,
+            Oper(_, a, b) => a.count_dice() + b.count_dice(),
+            Neg(a) | Not(a) | Bracket(a) | At(_, a) => a.count_dice(),
+            _ => 0,
+        }
+    }
+}
+
+fn print_list(l: &[Expr], rolls: &mut std::slice::Iter<Roll>) -> String {
+    let v: Vec<String> = l.iter().map(|e| e.print_rolls(rolls)).collect();
+    v.join(",")
 }
+
 #[cfg(test)]
 mod test_expr {
     use super::*;
+    use crate::dice::DiceMod;
+    use crate::scope::MAX_RANGE;
     #[test]
     fn test_expr_results() {
-        let scope = Scope::new();
+        let mut scope = Scope::new();
         let r: Expr = "(5 + 2)".parse().unwrap();
-        assert_eq!(r.eval(&scope), Ok(Value::Num(7)));
+        assert_eq!(r.eval(&mut scope), Ok(Value::Num(7)));
 
         let r: Expr = "(5 +2 *2)".parse().unwrap();
-        assert_eq!(r.eval(&scope), Ok(Value::Num(9)));
+        assert_eq!(r.eval(&mut scope), Ok(Value::Num(9)));
 
-        /*let r: Expr = "((3+4) * (10-1))".parse().unwrap();
-        assert_eq!(r.eval(&scope), Ok(Value::Num(63)));
+        let r: Expr = "((3+4) * (10-1))".parse().unwrap();
+        assert_eq!(r.eval(&mut scope), Ok(Value::Num(63)));
 
         let r: Expr = "(3 +5 +4 +7 +2)".parse().unwrap();
-        assert_eq!(r.eval(&scope), Ok(Value::Num(21)));
-        */
+        assert_eq!(r.eval(&mut scope), Ok(Value::Num(21)));
+    }
+
+    #[test]
+    fn test_expr_precedence() {
+        let mut scope = Scope::new();
+        let cases = [
+            ("10 - 2 - 3", Value::Num(5)),
+            ("100 / 10 / 5", Value::Num(2)),
+            ("2 * 3 + 4 * 5", Value::Num(26)),
+            ("10 - 2 * 3", Value::Num(4)),
+            ("20 / 2 * 5", Value::Num(50)),
+            ("7 % 3 + 1", Value::Num(2)),
+            ("1 + 2 > 2", Value::Bool(true)),
+            ("1 < 2 == 2 < 3", Value::Bool(true)),
+            ("true || false && false", Value::Bool(true)),
+            ("-2 * 3 + 10", Value::Num(4)),
+        ];
+        for (s, v) in cases.iter() {
+            let r: Expr = s.parse().unwrap();
+            assert_eq!(r.eval(&mut scope).as_ref(), Ok(v), "{}", s);
+        }
+    }
+
+    #[test]
+    fn test_branches() {
+        let mut scope = Scope::new();
+        let r: Expr = "if 1 > 2 { 1 } else if 2 > 1 { 2 } else { 3 }"
+            .parse()
+            .unwrap();
+        assert_eq!(r.eval(&mut scope), Ok(Value::Num(2)));
+
+        for (n, res) in [(1, "one"), (4, "few"), (5, "few"), (9, "many")].iter() {
+            let s = format!(
+                r#"match {} {{ 1 => "one", 2..=5 => "few", _ => "many" }}"#,
+                n
+            );
+            let r: Expr = s.parse().unwrap();
+            assert_eq!(r.eval(&mut scope), Ok(Value::str(res)), "{}", s);
+        }
+    }
+
+    #[test]
+    fn test_error_kinds() {
+        let mut scope = Scope::new();
+        let cases = [
+            ("1 / 0", ActionError::DivideByZero),
+            ("7 % 0", ActionError::DivideByZero),
+            (
+                r#"1 + "a""#,
+                ActionError::TypeMismatch {
+                    op: "add",
+                    a: "num",
+                    b: "str",
+                },
+            ),
+            (
+                "if 3 { 1 } else { 2 }",
+                ActionError::WrongType {
+                    expected: "bool",
+                    got: "num",
+                },
+            ),
+            ("missing", ActionError::PathNotFound(Proto::str("missing"))),
+            (
+                "for i in 0..1000000000 { }",
+                ActionError::RangeLimit(MAX_RANGE),
+            ),
+            (
+                "for i in 1000000000 { }",
+                ActionError::RangeLimit(MAX_RANGE),
+            ),
+            (
+                "for i in 0..=9223372036854775807 { }",
+                ActionError::Overflow,
+            ),
+            (
+                "d(6, 2000000)",
+                ActionError::DiceSides {
+                    max: crate::dice::MAX_SIDES,
+                    got: 2000000,
+                },
+            ),
+        ];
+        for (s, e) in cases.iter() {
+            let r: Expr = s.parse().unwrap();
+            let res = r.eval(&mut scope);
+            assert_eq!(res.as_ref().map_err(|e| e.kind()), Err(e), "{}", s);
+        }
+    }
+
+    #[test]
+    fn test_error_spans() {
+        let mut scope = Scope::new();
+        let r: Expr = "1 + (2 * missing)".parse().unwrap();
+        match r.eval(&mut scope) {
+            Err(ActionError::At(sp, e)) => {
+                assert_eq!(*e, ActionError::PathNotFound(Proto::str("missing")));
+                assert_eq!((sp.line, sp.col, sp.end_col), (0, 9, 16));
+            }
+            r => panic!("no span {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_deep_compare() {
+        let mut scope = Scope::new();
+        let cases = [
+            ("[1,2] == [1,2]", true),
+            ("[1,[2,3]] == [1,[2,3]]", true),
+            ("[1,2] != [1,2,3]", true),
+            ("[1,2] < [1,3]", true),
+            ("[1,2] < [1,2,0]", true),
+            ("{a:1,b:[2]} == {a:1,b:[2]}", true),
+            ("{a:1} == {a:2}", false),
+            ("{a:2} < {b:1}", true),
+            ("\"abc\" < \"abd\"", true),
+            ("\"b\" >= \"a\"", true),
+            ("1 == \"1\"", false),
+            ("[1,\"a\"] == [1,2]", false),
+        ];
+        for (s, v) in cases.iter() {
+            let r: Expr = s.parse().unwrap();
+            assert_eq!(r.eval(&mut scope), Ok(Value::Bool(*v)), "{}", s);
+        }
+        let r: Expr = "1 < \"a\"".parse().unwrap();
+        assert!(r.eval(&mut scope).is_err());
+
+        //protos count as fields, so instances match if their protos do
+        scope
+            .handle_input("M = {Dam:2}\nN = {Dam:2}\nR1 = new M\nR2 = new M\nR3 = new N\n")
+            .unwrap();
+        let cases = [
+            ("R1 == R2", true),
+            ("R1 == R3", true),
+            ("R1 == {}", false),
+            ("R1 == {Dam:2}", false),
+            ("R1 == {proto:$M}", true),
+        ];
+        for (s, v) in cases.iter() {
+            let r: Expr = s.parse().unwrap();
+            assert_eq!(r.eval(&mut scope), Ok(Value::Bool(*v)), "{}", s);
+        }
+
+        //cycles are too deep to compare, rather than equal
+        scope
+            .handle_input("A = {n:1}\nA.me = $A\nB = {n:2}\nB.me = $B\n")
+            .unwrap();
+        let r: Expr = "A == B".parse().unwrap();
+        match r.eval(&mut scope).map_err(|e| e.kind().clone()) {
+            Err(ActionError::CompareDepth(_)) => {}
+            r => panic!("cycle compared as {:?}", r),
+        }
+        //but a cycle is the same as itself
+        for s in &["A == A", "$A == $A", "contains([$A, $B], B)"] {
+            let r: Expr = s.parse().unwrap();
+            assert_eq!(r.eval(&mut scope), Ok(Value::Bool(true)), "{}", s);
+        }
+    }
+    /// Drops the spans, so parsed exprs can be compared by shape
+    fn bare(e: Expr) -> Expr {
+        match e {
+            Expr::At(_, e) => bare(*e),
+            Expr::Oper(o, a, b) => Expr::Oper(o, Box::new(bare(*a)), Box::new(bare(*b))),
+            e => e,
+        }
+    }
+
+    #[test]
+    fn test_dice_parse() {
+        let r: Expr = "2d6kh1".parse().unwrap();
+        assert_eq!(
+            bare(r),
+            Expr::Dice(Dice {
+                count: 2,
+                sides: 6,
+                md: Some(DiceMod::KeepHigh(1)),
+                explode: false,
+            })
+        );
+        let r: Expr = "d20Bonus".parse().unwrap();
+        assert_eq!(bare(r), Expr::Ident("d20Bonus".to_string()));
+        let r: Expr = "d".parse().unwrap();
+        assert_eq!(bare(r), Expr::Ident("d".to_string()));
+        let d = |explode| {
+            Box::new(Expr::Dice(Dice {
+                count: 2,
+                sides: 6,
+                md: None,
+                explode,
+            }))
+        };
+        let r: Expr = "2d6!=7".parse().unwrap();
+        assert_eq!(
+            bare(r),
+            Expr::Oper(Op::NotEqual, d(false), Box::new(Expr::Num(7)))
+        );
+        let r: Expr = "2d6!+1".parse().unwrap();
+        assert_eq!(
+            bare(r),
+            Expr::Oper(Op::Add, d(true), Box::new(Expr::Num(1)))
+        );
+        assert!("1000000000d6".parse::<Expr>().is_err());
+        assert!("2d18446744073709551615".parse::<Expr>().is_err());
+        assert!("2d1000001".parse::<Expr>().is_err());
+        assert!("d1000000".parse::<Expr>().is_ok());
+    }
+
+    #[test]
+    fn test_print_rolls() {
+        let r: Expr = "d20 + Bill.Str".parse().unwrap();
+        let roll = Roll {
+            dice: Dice::new(1, 20),
+            dies: vec![crate::dice::Die {
+                faces: vec![14],
+                kept: true,
+            }],
+        };
+        assert_eq!(r.print(), "d20+Bill.Str");
+        assert_eq!(r.print_rolls(&mut [roll].iter()), "[14]+Bill.Str");
+        let r: Expr = "max([2d6, :Bill.Str])".parse().unwrap();
+        assert_eq!(r.print(), "max([2d6,:Bill.Str])");
     }
 }
@@ -1,5 +1,7 @@
 mod action;
-//mod api_funcs;
+mod api_funcs;
+mod api_std;
+mod dice;
 mod ecs_ish;
 mod error;
 mod expr;
@@ -26,6 +28,9 @@ fn main() -> Result<(), failure::Error> {
         (@arg files: -f + takes_value ... "preloadfiles")
         (@arg tracker: -t +takes_value "Working Filename")
         (@arg nogui: -n "No Gui")
+        (@arg seed: -s --seed +takes_value "Seed for dice rolls")
+        (@arg rolls: -r --rolls +takes_value "File to replay dice from, and record them to")
+        (@arg strict: --strict "Run nothing from a file with parse errors")
     )
     .get_matches();
 
@@ -34,43 +39,100 @@ fn main() -> Result<(), failure::Error> {
     let fname = cfg.grab_local().arg("tracker").done();
 
     let mut scope = Scope::new();
-    if let Some(it) = clp.values_of("files") {
-        for fv in it {
-            scope.run_file(fv)?;
+    scope.set_strict(clp.is_present("strict"));
+    if let Some(sd) = cfg.grab().arg("seed").conf("seed").done() {
+        let sd = sd.parse::<u64>()?;
+        if sd > isize::max_value() as u64 {
+            return Err(failure::err_msg("Seed must fit in a num"));
         }
+        scope.set_seed(sd);
     }
 
-    if let Some(ref name) = fname {
-        scope.run_file(name)?;
+    let rolls_file = cfg.grab_local().arg("rolls").done();
+    if let Some(ref rf) = rolls_file {
+        if let Ok(s) = std::fs::read_to_string(rf) {
+            let faces = s
+                .split_whitespace()
+                .map(|f| f.parse::<isize>())
+                .collect::<Result<Vec<isize>, _>>()?;
+            scope.rng_mut().replay(faces);
+        }
     }
 
+    let mut files: Vec<String> = match clp.values_of("files") {
+        Some(it) => it.map(|s| s.to_string()).collect(),
+        None => Vec::new(),
+    };
+    files.extend(fname);
+
     /*    if !clp.is_present("nogui") {
         return screen::run_screen(scope).map_err(|e| e.into());
     }*/
 
+    //The rolls are saved however the session ends, so a failed load can be replayed
+    let res = run(&mut scope, &files);
+    if let Some(ref rf) = rolls_file {
+        let faces: Vec<String> = scope
+            .rng_mut()
+            .log()
+            .iter()
+            .map(|f| f.to_string())
+            .collect();
+        std::fs::write(rf, faces.join(" "))?;
+    }
+    res?;
+    println!("All Done");
+    Ok(())
+}
+
+/// Loads the files, then reads input until it runs out
+fn run(scope: &mut Scope, files: &[String]) -> Result<(), ActionError> {
+    for fv in files {
+        load_file(scope, fv)?;
+    }
+
     loop {
-        let input = match read_input() {
+        let input = match read_input(&scope.selection().to_string()) {
             Some(s) => s,
-            None => break,
+            None => return Ok(()),
         };
         if let Err(e) = scope.handle_input(&input) {
-            if let ActionError::ParseErr(ParseError {
-                code: ECode::EOF,
-                line: _,
-                col: _,
-            }) = e
-            {
-            } else {
-                println!("{}", e);
+            match e.kind() {
+                ActionError::ParseErrs(_, _, errs) if errs.iter().all(is_eof) => {}
+                _ => println!("{}", scope.render_err(&e)),
+            }
+        }
+    }
+}
+
+fn is_eof(e: &ParseError) -> bool {
+    match e.code {
+        ECode::EOF => true,
+        _ => false,
+    }
+}
+
+/// Parse errors are reported but the rest of the file still loads,
+/// anything else stops the tracker
+fn load_file<P: AsRef<Path> + std::fmt::Debug>(scope: &mut Scope, p: P) -> Result<(), ActionError> {
+    match scope.run_file(p) {
+        Ok(()) => Ok(()),
+        Err(e) => {
+            println!("{}", scope.render_err(&e));
+            match e.kind() {
+                ActionError::ParseErrs(_, _, _) => Ok(()),
+                _ => Err(e),
             }
         }
     }
-    println!("All Done");
-    Ok(())
 }
 
-pub fn read_input() -> Option<String> {
-    print!(">>");
+/// The prompt shows what is selected, eg "Bill.items >>"
+pub fn read_input(sel: &str) -> Option<String> {
+    match sel {
+        "" => print!(">>"),
+        s => print!("{} >>", s),
+    }
     std::io::stdout().flush().ok();
     let mut input = String::new();
     if let Ok(0) = std::io::stdin().read_line(&mut input) {
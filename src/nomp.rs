@@ -2,8 +2,10 @@ use gobble::*;
 use std::str::FromStr;
 
 use crate::action::Action;
+use crate::dice::{Dice, DiceMod, MAX_DICE, MAX_SIDES};
+use crate::error::Span;
 
-use crate::expr::{Expr, MapItem, Op};
+use crate::expr::{Expr, MapItem, MatchArm, Op, Pattern};
 
 pub fn pp_action<'a>(i: &LCChars<'a>) -> ParseRes<'a, Action> {
     let ps = s_tag("+")
@@ -14,29 +16,289 @@ pub fn pp_action<'a>(i: &LCChars<'a>) -> ParseRes<'a, Action> {
             .ig_then(maybe(num()))
             .then(ident())
             .map(|(nop, s)| Action::AddItem(-nop.unwrap_or(1), s)))
-        .or(s_tag("return").ig_then(p_expr).map(|e| Action::Return(e)));
+        .or(keyword("return").ig_then(p_expr).map(|e| Action::Return(e)));
     if let Ok((r, v)) = ps.parse(i) {
         return Ok((r, v));
     }
 
+    //Only whole statements, so data can still be called "seed" or "pop"
+    let cmd = keyword("seed")
+        .ig_then(maybe(p_expr))
+        .map(|e| Action::Seed(e))
+        .or(keyword("break").map(|_| Action::Break))
+        .or(keyword("continue").map(|_| Action::Continue))
+        .or(keyword("del").ig_then(p_expr).map(|e| Action::Delete(e)))
+        .or(keyword("mv")
+            .ig_then(p_expr)
+            .then(p_expr)
+            .map(|(a, b)| Action::Move(a, b)))
+        .or(keyword("rename")
+            .ig_then(p_expr)
+            .then(ident())
+            .map(|(a, n)| Action::Rename(a, n)))
+        .or(keyword("spawn")
+            .ig_then(p_expr)
+            .then(p_expr)
+            .map(|(n, a)| Action::Spawn(n, a)))
+        .or(keyword("pop").map(|_| Action::Pop))
+        .or(keyword("root").map(|_| Action::SelectRoot))
+        .or(keyword("gc")
+            .ig_then(keyword("every"))
+            .ig_then(p_expr)
+            .map(|e| Action::GcEvery(e)))
+        .or(keyword("gc").map(|_| Action::Gc))
+        .or(keyword("mem").map(|_| Action::Mem))
+        .or(keyword("mark").ig_then(ident()).map(|s| Action::Mark(s)))
+        .or(keyword("goto")
+            .ig_then(ident())
+            .map(|s| Action::GotoMark(s)));
+    if let Ok((r, v)) = cmd.parse(i) {
+        if stmt_end().parse(&r).is_ok() {
+            return Ok((r, v));
+        }
+    }
+
     let (r, l_ex) = p_expr.parse(i)?;
     if let Ok((r2, _)) = s_tag(":").parse(&r) {
-        return Ok((r2, Action::Select(l_ex)));
+        return Ok(select_block(r2, Action::Select(l_ex)));
     }
     if let Ok((r2, (oper, ex2))) = op().then_ig(tag("=")).then(p_expr).parse(&r) {
         return Ok((r2, Action::OpSet(oper, l_ex, ex2)));
     }
+    if let Ok((r2, t_ex)) = s_tag("->").ig_then(p_expr).parse(&r) {
+        return Ok((r2, Action::Link(l_ex, t_ex)));
+    }
     if let Ok((r2, r_ex)) = s_tag("=").ig_then(p_expr).parse(&r) {
         if let Ok((r3, _)) = s_tag(":").parse(&r2) {
-            return Ok((r3, Action::SetSelect(l_ex, r_ex)));
+            return Ok(select_block(r3, Action::SetSelect(l_ex, r_ex)));
         }
         return Ok((r2, Action::Set(l_ex, r_ex)));
     }
     Ok((r, Action::Resolve(l_ex)))
 }
 
+/// Looks for the end of a statement without taking it,
+/// a line break, the end of input, or the close of a block
+fn stmt_end() -> impl Parser<()> {
+    l_break()
+        .or(wsc().ig_then(eoi))
+        .or(s_tag("}").map(|_| ()))
+        .or(s_tag("]").map(|_| ()))
+}
+
+/// `Name: { .HP=10 }` scopes the selection to the braces
+fn select_block<'a>(i: LCChars<'a>, sel: Action) -> (LCChars<'a>, Action) {
+    match code_block().parse(&i) {
+        Ok((r, body)) => (r, Action::SelectBlock(Box::new(sel), body)),
+        Err(_) => (i, sel),
+    }
+}
+
+/// A word that must not run on into an identifier, eg "seed" but not "seeds"
+fn keyword(k: &'static str) -> impl Parser<()> {
+    s_tag(k).ig_then(read_fs(is_alpha_num, 0).try_map(|s| match s.len() {
+        0 => Ok(()),
+        _ => Err(ECode::SMess("Not a keyword")),
+    }))
+}
+
+/// An action wrapped with where it was found, the src is set once the whole input is parsed
+/// `## doc` lines before a field being set are kept as its doc
+pub fn pp_stmt<'a>(i: &LCChars<'a>) -> ParseRes<'a, Action> {
+    let (r, docs) = repeat(doc_line(), 0).parse(i)?;
+    let (r, _) = wsc().parse(&r)?;
+    let (r2, ac) = pp_action(&r)?;
+    let ac = match ac {
+        Action::Set(_, _) | Action::OpSet(_, _, _) | Action::Link(_, _) if docs.len() > 0 => {
+            Action::Doc(docs.join("\n"), Box::new(ac))
+        }
+        ac => ac,
+    };
+    Ok((r2.clone(), Action::At(span(&r, &r2), Box::new(ac))))
+}
+
+/// A whole line of `## doc`, wsc would skip it as a comment
+fn doc_line() -> impl Parser<String> {
+    read_fs(|c| c == ' ' || c == '\t', 0)
+        .ig_then(tag("##"))
+        .ig_then(read_fs(|c| c != '\n', 0))
+        .then_ig(tag("\n"))
+        .map(|s| s.trim().to_string())
+}
+
+/// From the start of one input to the start of the other
+fn span(start: &LCChars, end: &LCChars) -> Span {
+    let (line, col) = start.lc();
+    let (end_line, end_col) = end.lc();
+    Span {
+        src: 0,
+        line,
+        col,
+        end_line,
+        end_col,
+    }
+}
+
+/// Parses every statement it can, after a bad one it skips ahead to resynchronise,
+/// so one typo doesn't hide the rest of the file
+pub fn pp_stmts(s: &str) -> (Vec<Action>, Vec<ParseError>) {
+    let mut errs = Vec::new();
+    let (_, res) = indented_stmts(LCChars::str(s), 0, &mut errs);
+    (res, errs)
+}
+
+/// Statements up to the first line indented less than `indent`.
+/// A selection followed by lines indented deeper takes those lines as its block,
+/// so `Player:` then `    .HP=10` only selects Player for the indented lines
+fn indented_stmts<'a>(
+    mut it: LCChars<'a>,
+    indent: usize,
+    errs: &mut Vec<ParseError>,
+) -> (LCChars<'a>, Vec<Action>) {
+    let mut res = Vec::new();
+    loop {
+        if wsc().ig_then(eoi).parse(&it).is_ok() {
+            return (it, res);
+        }
+        //a doc line is kept for the statement under it
+        if doc_line().parse(&it).is_err() {
+            if let Ok((r, _)) = l_break().parse(&it) {
+                it = r;
+                continue;
+            }
+        }
+        let col = line_indent(&it);
+        if col < indent {
+            return (it, res);
+        }
+        match pp_stmt.then_ig(l_break().or(wsc().ig_then(eoi))).parse(&it) {
+            Ok((r, Action::At(sp, a))) => {
+                it = r;
+                match *a {
+                    Action::Select(_) | Action::SetSelect(_, _) if next_indent(&it) > col => {
+                        let (r, body) = indented_stmts(it, col + 1, errs);
+                        it = r;
+                        let sb = Action::SelectBlock(a, body);
+                        res.push(Action::At(sp, Box::new(sb)));
+                    }
+                    a => res.push(Action::At(sp, Box::new(a))),
+                }
+            }
+            Ok((r, a)) => {
+                res.push(a);
+                it = r;
+            }
+            Err(e) => {
+                errs.push(e);
+                it = skip_stmt(it);
+            }
+        }
+    }
+}
+
+/// The column the next statement starts at
+fn line_indent(it: &LCChars) -> usize {
+    match read_fs(|c| c == ' ' || c == '\t', 0).parse(it) {
+        Ok((r, _)) => r.lc().1,
+        Err(_) => it.lc().1,
+    }
+}
+
+/// The indent of the next line that has anything on it, 0 at the end
+fn next_indent(it: &LCChars) -> usize {
+    let mut it = it.clone();
+    while let Ok((r, _)) = l_break().parse(&it) {
+        it = r;
+    }
+    match wsc().ig_then(eoi).parse(&it) {
+        Ok(_) => 0,
+        Err(_) => line_indent(&it),
+    }
+}
+
+/// Skips to the next line break outside of braces and strings,
+/// or past a closing brace that was never opened
+fn skip_stmt<'a>(mut it: LCChars<'a>) -> LCChars<'a> {
+    let mut depth = 0;
+    let mut quoted = false;
+    while let Some(c) = it.next() {
+        match c {
+            '\\' if quoted => {
+                it.next();
+            }
+            '"' => quoted = !quoted,
+            _ if quoted => {}
+            '#' => {
+                while it.next().unwrap_or('\n') != '\n' {}
+                if depth == 0 {
+                    return it;
+                }
+            }
+            '{' | '[' => depth += 1,
+            '}' | ']' if depth == 0 => return it,
+            '}' | ']' => depth -= 1,
+            '\n' | ';' if depth == 0 => return it,
+            _ => {}
+        }
+    }
+    it
+}
+
+/// `# to line end` or `/* block */`, gives the comment text
+fn comment() -> impl Parser<String> {
+    let line = tag("#").ig_then(read_fs(|c| c != '\n', 0));
+    let star = read_fs(|c| c == '*', 1)
+        .then(read_fs(|c| c != '*' && c != '/', 1))
+        .map(|(mut a, b)| {
+            a.push_str(&b);
+            a
+        });
+    let block = tag("/*")
+        .ig_then(repeat(read_fs(|c| c != '*', 1).or(star), 0))
+        .then_ig(read_fs(|c| c == '*', 1))
+        .then_ig(tag("/"))
+        .map(|v| v.join(""));
+    line.or(block)
+}
+
+/// Whitespace within a line, comments count as whitespace
+fn wsc() -> impl Parser<()> {
+    repeat(
+        read_fs(|c| c == ' ' || c == '\t' || c == '\r', 1)
+            .map(|_| ())
+            .or(comment().map(|_| ())),
+        0,
+    )
+    .map(|_| ())
+}
+
+/// Whitespace including line breaks, for inside brackets.
+/// Gives back the doc comments (`## doc`) it passed
+fn gap() -> impl Parser<Vec<String>> {
+    repeat(
+        read_fs(char::is_whitespace, 1)
+            .map(|_| None)
+            .or(comment().map(|c| match c.starts_with('#') {
+                true => Some(c[1..].trim().to_string()),
+                false => None,
+            })),
+        0,
+    )
+    .map(|v| v.into_iter().filter_map(|d| d).collect())
+}
+
+/// Shadows gobble's s_tag so comments are skipped too
+fn s_tag(s: &'static str) -> impl Parser<&'static str> {
+    wsc().ig_then(tag(s))
+}
+
+/// A tag that may be on a later line
+fn n_tag(s: &'static str) -> impl Parser<&'static str> {
+    gap().ig_then(tag(s))
+}
+
 fn ident() -> impl Parser<String> {
-    ws(0)
+    wsc()
         .ig_then(read_fs(is_alpha, 1))
         .then(read_fs(is_alpha_num, 0))
         .map(|(mut a, b)| {
@@ -45,109 +307,369 @@ fn ident() -> impl Parser<String> {
         })
 }
 fn num() -> impl Parser<isize> {
-    ws(0)
+    wsc()
         .ig_then(read_fs(is_num, 1))
         .try_map(|ns| isize::from_str(&ns).map_err(|_| ECode::SMess("Not a Num")))
 }
 
+fn unum() -> impl Parser<usize> {
+    read_fs(is_num, 1).try_map(|ns| usize::from_str(&ns).map_err(|_| ECode::SMess("Not a Num")))
+}
+
+fn dice_mod() -> impl Parser<DiceMod> {
+    (tag("kh").ig_then(unum()).map(|n| DiceMod::KeepHigh(n)))
+        .or(tag("kl").ig_then(unum()).map(|n| DiceMod::KeepLow(n)))
+        .or(tag("k").ig_then(unum()).map(|n| DiceMod::KeepHigh(n)))
+        .or(tag("dh").ig_then(unum()).map(|n| DiceMod::DropHigh(n)))
+        .or(tag("dl").ig_then(unum()).map(|n| DiceMod::DropLow(n)))
+        .or(tag("d").ig_then(unum()).map(|n| DiceMod::DropLow(n)))
+}
+
+/// 3d6, d20, 4d6kh3, 2d10!
+/// Must not run on into a name, so "d20Bonus" is an identifier,
+/// and in "2d6!=7" the "!" belongs to the comparison
+fn dice<'a>(i: &LCChars<'a>) -> ParseRes<'a, Dice> {
+    let (r, (count, sides)) = maybe(unum())
+        .then_ig(tag("d"))
+        .then(unum())
+        .try_map(|(count, sides)| {
+            let count = count.unwrap_or(1);
+            if sides == 0 {
+                return Err(ECode::SMess("Dice need at least one side"));
+            }
+            if sides > MAX_SIDES {
+                return Err(ECode::SMess("Too many sides on a die"));
+            }
+            if count > MAX_DICE {
+                return Err(ECode::SMess("Too many dice in one roll"));
+            }
+            Ok((count, sides))
+        })
+        .parse(i)?;
+    let (r, explode) = match tag("!").parse(&r) {
+        Ok((r2, _)) if tag("=").parse(&r2).is_err() => (r2, true),
+        _ => (r, false),
+    };
+    let (r, md) = maybe(dice_mod()).parse(&r)?;
+    let (r, _) = read_fs(is_alpha_num, 0)
+        .try_map(|s| match s.len() {
+            0 => Ok(()),
+            _ => Err(ECode::SMess("Dice cannot run on into a name")),
+        })
+        .parse(&r)?;
+    Ok((
+        r,
+        Dice {
+            count,
+            sides,
+            md,
+            explode,
+        },
+    ))
+}
+
 pub fn l_break() -> impl Parser<()> {
-    ws(0).then_ig(tag(";").or(tag("\n")))
+    wsc().then_ig(tag(";").or(tag("\n")))
 }
 
 fn op() -> impl Parser<Op> {
-    ws(0)
+    wsc()
         .ig_then(
             tag("+")
                 .or(tag("-"))
                 .or(tag("*"))
                 .or(tag("/"))
+                .or(tag("%"))
                 .or(tag("=="))
-                .or(tag("."))
+                .or(tag("!="))
+                .or(tag(">="))
+                .or(tag("<="))
                 .or(tag(">"))
                 .or(tag("<"))
-                .or(tag("/")),
+                .or(tag("&&"))
+                .or(tag("||")),
         )
         .try_map(|c| Op::from_str(c))
 }
 
 fn list() -> impl Parser<Expr> {
     s_tag("[")
-        .ig_then(sep(p_expr, s_tag(","), false))
-        .then_ig(s_tag("]"))
+        .ig_then(sep(gap().ig_then(p_expr), n_tag(","), false))
+        .then_ig(n_tag("]"))
         .map(|l| Expr::List(l))
 }
 
 fn map_item() -> impl Parser<MapItem> {
-    ident()
+    gap()
+        .then(ident())
         .then_ig(s_tag(":"))
         .then(p_expr)
-        .map(|(k, v)| MapItem { k, v })
+        .map(|((docs, k), v)| MapItem {
+            k,
+            v,
+            doc: match docs.len() {
+                0 => None,
+                _ => Some(docs.join("\n")),
+            },
+        })
 }
 
 fn map() -> impl Parser<Expr> {
     s_tag("{")
         .ig_then(repeat(map_item().then_ig(maybe(s_tag(","))), 0))
-        .then_ig(s_tag("}"))
+        .then_ig(n_tag("}"))
         .map(|e| Expr::Map(e))
 }
 
 fn code_block() -> impl Parser<Vec<Action>> {
-    s_tag("{")
-        .ig_then(sep_until(maybe(pp_action), l_break(), s_tag("}")))
+    block("{", "}")
+}
+
+fn block(open: &'static str, close: &'static str) -> impl Parser<Vec<Action>> {
+    s_tag(open)
+        .ig_then(sep_until(maybe(pp_stmt), l_break(), s_tag(close)))
         .map(|v| v.into_iter().filter_map(|a| a).collect())
 }
 
-fn if_clause() -> impl Parser<Expr> {
-    //Todo Consider Elif: Done right, wont even effect anything else
-    s_tag("if")
+fn params() -> impl Parser<Vec<String>> {
+    s_tag("(")
+        .ig_then(sep(ident(), s_tag(","), false))
+        .then_ig(s_tag(")"))
+}
+
+fn call_args() -> impl Parser<Vec<Expr>> {
+    tag("(")
+        .ig_then(sep(gap().ig_then(p_expr), n_tag(","), false))
+        .then_ig(n_tag(")"))
+}
+
+fn expr_def() -> impl Parser<Expr> {
+    s_tag("expr")
+        .ig_then(params())
+        .then(s_tag("(").ig_then(p_expr).then_ig(s_tag(")")))
+        .map(|(p, e)| Expr::ExprDef(p, Box::new(e)))
+        .or(s_tag("expr")
+            .ig_then(s_tag("("))
+            .ig_then(p_expr)
+            .then_ig(s_tag(")"))
+            .map(|e| Expr::ExprDef(Vec::new(), Box::new(e))))
+}
+
+fn func_def() -> impl Parser<Expr> {
+    //sheets written as fn(a)[...] are also accepted
+    s_tag("fn")
+        .ig_then(params())
+        .then(code_block().or(block("[", "]")))
+        .map(|(p, acs)| Expr::FuncDef(p, acs))
+}
+
+fn path_node() -> impl Parser<Expr> {
+    ident()
+        .map(|s| Expr::Ident(s))
+        .or(num().map(|n| Expr::Num(n)))
+        .or(s_tag("-").ig_then(num()).map(|n| Expr::Num(-n)))
+        .or(s_tag("(")
+            .ig_then(p_expr)
+            .then_ig(s_tag(")"))
+            .map(|e| Expr::Bracket(Box::new(e))))
+}
+
+/// ".1..3", ".-2.." or ".0..=2" after a list or string,
+/// bounds beyond a literal go in brackets, eg ".(n-2).."
+fn slice_range() -> impl Parser<((Expr, Option<&'static str>), Option<Expr>)> {
+    s_tag(".")
+        .ig_then(slice_bound())
+        .then_ig(tag(".."))
+        .then(maybe(tag("=")))
+        .then(maybe(slice_bound()))
+}
+
+fn slice_bound() -> impl Parser<Expr> {
+    int().map(|n| Expr::Num(n)).or(s_tag("(")
+        .ig_then(p_expr)
+        .then_ig(s_tag(")"))
+        .map(|e| Expr::Bracket(Box::new(e))))
+}
+
+//a fn, not impl Parser, as "else if" makes it recursive
+fn if_clause<'a>(i: &LCChars<'a>) -> ParseRes<'a, Expr> {
+    keyword("if")
         .ig_then(p_expr)
         .then(code_block())
-        .then(maybe(s_tag("else").ig_then(code_block())))
-        .map(|((bex, lblk), rop)| {
-            if let Some(rblk) = rop {
-                Expr::If(Box::new(bex), lblk, rblk)
-            } else {
-                Expr::If(Box::new(bex), lblk, Vec::new())
-            }
+        .then(maybe(keyword("else").ig_then(
+            code_block().or(if_clause.map(|e| vec![Action::Resolve(e)])),
+        )))
+        .map(|((bex, lblk), rop)| Expr::If(Box::new(bex), lblk, rop.unwrap_or(Vec::new())))
+        .parse(i)
+}
+
+fn int() -> impl Parser<isize> {
+    maybe(s_tag("-"))
+        .then(num())
+        .map(|(neg, n)| if neg.is_some() { -n } else { n })
+}
+
+fn quoted() -> impl Parser<String> {
+    tag("\"").ig_then(esc('"', '\\').e_map('t', '\t'))
+}
+
+fn pattern() -> impl Parser<Pattern> {
+    (s_tag("_").map(|_| Pattern::Any))
+        .or(int()
+            .then_ig(s_tag(".."))
+            .then(maybe(tag("=")))
+            .then(int())
+            .map(|((a, inc), b)| Pattern::Range(a, b, inc.is_some())))
+        .or(int().map(|n| Pattern::Num(n)))
+        .or(wsc().ig_then(quoted()).map(|s| Pattern::Str(s)))
+        .or(keyword("true").map(|_| Pattern::Bool(true)))
+        .or(keyword("false").map(|_| Pattern::Bool(false)))
+}
+
+fn match_arm() -> impl Parser<MatchArm> {
+    sep(pattern(), s_tag("|"), false)
+        .then_ig(s_tag("=>"))
+        .then(code_block().or(p_expr.map(|e| vec![Action::Resolve(e)])))
+        .map(|(pats, body)| MatchArm { pats, body })
+}
+
+fn is_arm_sep(c: char) -> bool {
+    c.is_whitespace() || c == ','
+}
+
+fn match_expr() -> impl Parser<Expr> {
+    keyword("match")
+        .ig_then(p_expr)
+        .then_ig(s_tag("{"))
+        .then(repeat(read_fs(is_arm_sep, 0).ig_then(match_arm()), 0))
+        .then_ig(read_fs(is_arm_sep, 0))
+        .then_ig(s_tag("}"))
+        .map(|(e, arms)| Expr::Match(Box::new(e), arms))
+}
+
+/// "a" or "a..b" or "a..=b"
+fn range_expr() -> impl Parser<Expr> {
+    p_expr
+        .then(maybe(s_tag("..").ig_then(maybe(tag("="))).then(p_expr)))
+        .map(|(a, rop)| match rop {
+            Some((inc, b)) => Expr::Range(Box::new(a), Box::new(b), inc.is_some()),
+            None => a,
         })
 }
 
+fn for_loop() -> impl Parser<Expr> {
+    keyword("for")
+        .ig_then(sep(ident(), s_tag(","), false))
+        .then_ig(keyword("in"))
+        .then(range_expr())
+        .then(code_block())
+        .map(|((names, it), blk)| Expr::For(names, Box::new(it), blk))
+}
+
+fn while_loop() -> impl Parser<Expr> {
+    keyword("while")
+        .ig_then(p_expr)
+        .then(code_block())
+        .map(|(cond, blk)| Expr::While(Box::new(cond), blk))
+}
+
 //must not be impl<Parser<Expr>> to avoid giant objects
-fn p_expr_l<'a>(i: &LCChars<'a>) -> ParseRes<'a, Expr> {
+fn p_atom<'a>(i: &LCChars<'a>) -> ParseRes<'a, Expr> {
     let ps = (tag("true").map(|_| Expr::Bool(true)))
         .or(tag("false").map(|_| Expr::Bool(false)))
+        .or(keyword("null").map(|_| Expr::Null))
+        .or(dice.map(|d| Expr::Dice(d)))
         .or(num().map(|v| Expr::Num(v)))
-        .or(tag("\"")
-            .ig_then(esc('"', '\\').e_map('t', '\t'))
-            .map(|s| Expr::Str(s)))
+        .or(quoted().map(|s| Expr::Str(s)))
         .or(s_tag(".")
-            .ig_then(p_expr)
+            .ig_then(p_atom)
             .map(|e| Expr::DotStart(Box::new(e))))
         .or(s_tag(":")
-            .ig_then(p_expr)
+            .ig_then(p_atom)
             .map(|e| Expr::Rooted(Box::new(e))))
         .or(s_tag("-").ig_then(p_expr_l).map(|e| Expr::Neg(Box::new(e))))
-        .or(s_tag("$").ig_then(p_expr).map(|e| Expr::Deref(Box::new(e))))
+        .or(s_tag("!").ig_then(p_expr_l).map(|e| Expr::Not(Box::new(e))))
+        .or(s_tag("$")
+            .ig_then(p_expr_l)
+            .map(|e| Expr::Deref(Box::new(e))))
+        .or(s_tag("&")
+            .ig_then(p_expr_l)
+            .map(|e| Expr::Weak(Box::new(e))))
+        .or(keyword("new")
+            .ig_then(p_expr_l)
+            .map(|e| Expr::New(Box::new(e))))
         .or(s_tag("(")
             .ig_then(p_expr)
             .then_ig(s_tag(")"))
             .map(|e| Expr::Bracket(Box::new(e))))
         .or(list())
         .or(map())
-        .or(if_clause())
+        .or(if_clause)
+        .or(match_expr())
+        .or(for_loop())
+        .or(while_loop())
+        .or(expr_def())
+        .or(func_def())
         .or(ident().map(|e| Expr::Ident(e)));
 
-    ws(0).ig_then(ps).parse(i)
+    wsc().ig_then(ps).parse(i)
+}
+
+/// An atom followed by any number of ".child" or "(args)" postfixes
+fn p_expr_l<'a>(i: &LCChars<'a>) -> ParseRes<'a, Expr> {
+    let (mut r, mut res) = p_atom(i)?;
+    loop {
+        if let Ok((r2, args)) = call_args().parse(&r) {
+            res = Expr::Call(Box::new(res), args);
+            r = r2;
+            continue;
+        }
+        if let Ok((r2, ((a, inc), b))) = slice_range().parse(&r) {
+            let b = b.map(|b| Box::new(b));
+            res = Expr::Slice(Box::new(res), Box::new(a), b, inc.is_some());
+            r = r2;
+            continue;
+        }
+        if let Ok((r2, n)) = s_tag(".").ig_then(path_node()).parse(&r) {
+            res = Expr::Oper(Op::Dot, Box::new(res), Box::new(n));
+            r = r2;
+            continue;
+        }
+        return Ok((r, res));
+    }
 }
 
 //Cannot be a ->impl Parser() to avoid infinite struct creation
 pub fn p_expr<'a>(i: &LCChars<'a>) -> ParseRes<'a, Expr> {
-    ws(0)
-        .ig_then(p_expr_l)
-        .then(maybe(op().then(p_expr)))
-        .map(|(l, r)| match r {
-            Some((o, re)) => re.add_left(l, o),
-            None => l,
-        })
-        .parse(i)
+    p_binary(i, 0)
+}
+
+/// Precedence climbing: only takes operators binding at least as tight as min_prec.
+/// If an operator has no valid right side it is left unparsed, so "a += 1" still works
+fn p_binary<'a>(i: &LCChars<'a>, min_prec: u8) -> ParseRes<'a, Expr> {
+    let (start, _) = wsc().parse(i)?;
+    let (mut r, res) = p_expr_l(&start)?;
+    let mut res = Expr::At(span(&start, &r), Box::new(res));
+    loop {
+        let (r2, o) = match op().parse(&r) {
+            Ok(ro) => ro,
+            Err(_) => return Ok((r, res)),
+        };
+        if o.prec() < min_prec {
+            return Ok((r, res));
+        }
+        //prec + 1 makes every operator left associative
+        let (r3, rt) = match p_binary(&r2, o.prec() + 1) {
+            Ok(rr) => rr,
+            Err(_) => return Ok((r, res)),
+        };
+        let sp = span(&start, &r3);
+        res = Expr::At(sp, Box::new(Expr::Oper(o, Box::new(res), Box::new(rt))));
+        r = r3;
+    }
+}
+
+/// An expression that must use the whole input
+pub fn full_expr() -> impl Parser<Expr> {
+    p_expr.then_ig(wsc()).then_ig(eoi)
 }
@@ -7,7 +7,10 @@ use std::str::FromStr;
 pub enum ProtoNode {
     Num(usize),
     Str(String),
-    Deref,
+    /// Counts from the end of a list, Back(1) is the last item
+    Back(usize),
+    /// The `##` comment on the named sibling, no path can name it so it never hides a field
+    Doc(String),
 }
 
 impl ProtoNode {
@@ -18,8 +21,8 @@ impl ProtoNode {
     pub fn as_string(&self) -> String {
         match self {
             ProtoNode::Num(n) => n.to_string(),
-            ProtoNode::Str(s) => s.clone(),
-            ProtoNode::Deref => String::new(),
+            ProtoNode::Str(s) | ProtoNode::Doc(s) => s.clone(),
+            ProtoNode::Back(n) => format!("-{}", n),
         }
     }
 
@@ -27,7 +30,28 @@ impl ProtoNode {
         match self {
             ProtoNode::Num(n) => Some(*n),
             ProtoNode::Str(s) => usize::from_str(s).ok(),
-            ProtoNode::Deref => None,
+            ProtoNode::Back(_) | ProtoNode::Doc(_) => None,
+        }
+    }
+
+    pub fn is_doc(&self) -> bool {
+        match self {
+            ProtoNode::Doc(_) => true,
+            _ => false,
+        }
+    }
+
+    /// "proto" and docs say how a map behaves and what it means, they are not its items
+    pub fn is_meta(&self) -> bool {
+        self.is_doc() || self == &ProtoNode::str("proto")
+    }
+
+    /// Where this points in a list of the given length, may be past the end
+    pub fn list_index(&self, len: usize) -> Option<usize> {
+        match self {
+            ProtoNode::Num(n) => Some(*n),
+            ProtoNode::Back(n) if *n > 0 => len.checked_sub(*n),
+            _ => None,
         }
     }
 }
@@ -48,7 +72,8 @@ impl Display for Proto {
             match node {
                 ProtoNode::Num(n) => write!(f, "{}", n)?,
                 ProtoNode::Str(s) => write!(f, "{}", s.replace(".", "\\."))?,
-                ProtoNode::Deref => write!(f, "DEREF")?,
+                ProtoNode::Back(n) => write!(f, "-{}", n)?,
+                ProtoNode::Doc(s) => write!(f, "##{}", s)?,
             }
         }
         Ok(())
@@ -84,10 +109,14 @@ impl Proto {
     pub fn num(n: usize) -> Self {
         Self::one(ProtoNode::Num(n))
     }
-    pub fn dr() -> Self {
-        Self::one(ProtoNode::Deref)
-    }
 
+    /// Negative numbers count back from the end of a list
+    pub fn index(n: isize) -> Self {
+        match n {
+            n if n < 0 => Self::one(ProtoNode::Back(-n as usize)),
+            n => Self::num(n as usize),
+        }
+    }
     pub fn dot(mut self) -> Self {
         self.dots += 1;
         self
@@ -103,6 +132,29 @@ impl Proto {
         }
     }
 
+    pub fn len(&self) -> usize {
+        self.v.len()
+    }
+
+    /// Whether this is the other path or somewhere below it
+    pub fn starts_with(&self, o: &Proto) -> bool {
+        self.root == o.root && self.dots == o.dots && self.v.starts_with(&o.v)
+    }
+
+    /// The same place, moved from below one path to below another
+    pub fn rebase(&self, from: &Proto, to: &Proto) -> Option<Proto> {
+        if !self.starts_with(from) {
+            return None;
+        }
+        let mut res = to.clone();
+        res.v.extend(self.v[from.v.len()..].iter().cloned());
+        Some(res)
+    }
+
+    pub fn last(&self) -> Option<&ProtoNode> {
+        self.v.last()
+    }
+
     pub fn parent(&self) -> Self {
         let mut res = self.clone();
         if res.v.len() > 0 {
@@ -1,24 +1,100 @@
-use crate::action::{AcResult, AcReturn, Action};
-use crate::ecs_ish::{GenData, GenManager};
-use crate::error::ActionError;
+use crate::action::{outside_loop, AcResult, AcReturn, Action};
+use crate::api_funcs;
+use crate::dice::{DiceRng, Roll};
+use crate::ecs_ish::{GenData, GenManager, GenStats};
+use crate::error::{ActionError, Source};
 use crate::expr::Expr;
 use crate::proto::{Proto, ProtoNode, ProtoP};
 use crate::value::Value;
 //use gobble::{LCChars, Parser};
-//use std::collections::BTreeMap;
+use std::collections::BTreeMap;
 use std::fmt::Debug;
 use std::path::Path;
 
+/// How far reads will follow "proto" fields, protects against proto cycles
+const MAX_PROTO_DEPTH: usize = 64;
+
+/// How many links a read will follow, protects against link cycles
+const MAX_LINK_DEPTH: usize = 64;
+
+/// Stops a runaway while loop from hanging the tracker
+const MAX_WHILE: usize = 100_000;
+
+/// Most nums a range or counted loop may hold, as many as a while loop may run
+pub const MAX_RANGE: usize = MAX_WHILE;
+
+/// Most copies one spawn will make
+const MAX_SPAWN: usize = 1000;
+/// REPL inputs and bad path strings kept for errors to point into, older ones are dropped
+/// REPL inputs kept for errors to point into, older ones are dropped
+const KEEP_INPUTS: usize = 64;
+
+/// Allocations between automatic garbage collections
+const GC_EVERY: usize = 10_000;
+
+/// The nums from a up to b, refused when too long to build so a typo can't eat all memory
+pub fn num_range(a: isize, b: isize, inc: bool) -> Result<std::ops::Range<isize>, ActionError> {
+    let b = match inc {
+        true => b.checked_add(1).ok_or(ActionError::Overflow)?,
+        false => b,
+    };
+    if b > a && b.checked_sub(a).map_or(true, |n| n as usize > MAX_RANGE) {
+        return Err(ActionError::RangeLimit(MAX_RANGE));
+    }
+    Ok(a..b)
+}
+
 #[derive(Debug)]
 pub struct Base {
     gd: GenData,
     swap_off: bool,
+    path: Proto, //what was selected, for showing the user
+    local: bool, //inside a fn or block scope, so not reachable from the root
+}
+
+/// Why a read found nothing
+enum Miss {
+    Missing,
+    /// Links went round too often, holds the last one followed
+    Cycle(Proto),
+}
+
+/// What "self" and "parent" refer to inside a called expr or fn
+#[derive(Debug)]
+pub struct Owner {
+    this: GenData,
+    parent: Option<GenData>,
+}
+
+/// The state of memory, and anything leaked or left dangling
+#[derive(Debug)]
+pub struct Audit {
+    pub stats: GenStats,
+    pub unreachable: usize,
+    pub dangling: Vec<Proto>, //where handles to freed values are held
+}
+
+impl Audit {
+    pub fn is_clean(&self) -> bool {
+        self.unreachable == 0 && self.dangling.len() == 0
+    }
 }
 
 #[derive(Debug)]
 pub struct Scope {
     bases: Vec<Base>, //swapoff
     gm: GenManager,
+    rolls: Vec<Roll>, //made during the current action
+    rng: DiceRng,
+    sources: BTreeMap<usize, Source>,
+    next_src: usize,
+    marks: BTreeMap<String, Proto>,
+    /// Run nothing from a source with parse errors, rather than the good statements
+    strict: bool,
+    gc_every: usize, //0 for never
+    gc_asked: bool,
+    mem_asked: bool,
+    running: usize, //nested sources, collecting is only safe between top level actions
 }
 
 impl Scope {
@@ -29,8 +105,20 @@ impl Scope {
             bases: vec![Base {
                 gd,
                 swap_off: false,
+                path: Proto::new(),
+                local: false,
             }],
             gm,
+            rolls: Vec::new(),
+            rng: DiceRng::from_entropy(),
+            sources: BTreeMap::new(),
+            next_src: 0,
+            marks: BTreeMap::new(),
+            strict: false,
+            gc_every: GC_EVERY,
+            gc_asked: false,
+            mem_asked: false,
+            running: 0,
         }
     }
 
@@ -41,6 +129,8 @@ impl Scope {
         self.bases.push(Base {
             gd: self.gm.push(Value::map()),
             swap_off: false,
+            path: Proto::new(),
+            local: true,
         });
         let res = f(self);
         loop {
@@ -53,78 +143,512 @@ impl Scope {
     }
 
     pub fn handle_input(&mut self, s: &str) -> Result<(), ActionError> {
-        //let mut ss = LCChars::str(s);
-        use crate::nomp::*;
-        use gobble::*;
-        let ac = sep_until(maybe(pp_action), l_break(), eoi);
-        let v: Vec<Action> = ac.parse_s(s)?.into_iter().filter_map(|a| a).collect();
+        self.run_source("<input>", s)
+    }
+
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    /// Parses and runs the text, keeping it so errors can point back into it.
+    /// Good statements still run around bad ones unless strict,
+    /// the parse errors are all returned together at the end
+    /// Keeps the text so errors can point into it, giving the index spans refer to
+    pub fn add_source(&mut self, name: &str, s: &str) -> usize {
+        let src = self.next_src;
+        self.next_src += 1;
+        self.sources.insert(
+            src,
+            Source {
+                name: name.to_string(),
+                text: s.to_string(),
+            },
+        );
+        self.drop_old_inputs();
+        src
+    }
+
+    pub fn run_source(&mut self, name: &str, s: &str) -> Result<(), ActionError> {
+        let src = self.add_source(name, s);
+        let (mut v, errs) = crate::nomp::pp_stmts(s);
+        if errs.len() > 0 && self.strict {
+            return Err(ActionError::ParseErrs(src, name.to_string(), errs));
+        }
+        for a in v.iter_mut() {
+            a.set_src(src);
+        }
+        self.running += 1;
         for a in v {
+            self.rolls.clear();
             match self.do_action(&a) {
                 //TODO consider writing file
-                Ok(Value::Null) => {}
+                Ok(Value::Null) => self.print_rolls(None),
                 Ok(v) => {
+                    let ex = match a.inner() {
+                        Action::Resolve(ex) => Some(ex),
+                        _ => None,
+                    };
+                    self.print_rolls(ex);
                     println!("{}", v.print(0, &self.gm));
+                    self.gm.drop(v);
                 }
-                Err(e) => println!("Error {}", e),
+                Err(e) => println!("Error {}", self.render_err(&e)),
+            }
+            if self.running == 1 {
+                self.maybe_report_mem();
+                self.maybe_collect();
             }
         }
-        Ok(())
+        self.running -= 1;
+        match errs.len() {
+            0 => Ok(()),
+            _ => Err(ActionError::ParseErrs(src, name.to_string(), errs)),
+        }
+    }
+
+    /// Allocations between automatic collections, 0 for never
+    pub fn set_gc_every(&mut self, n: usize) {
+        self.gc_every = n;
+    }
+
+    /// Frees everything not reachable from the bases
+    pub fn collect_garbage(&mut self) -> usize {
+        self.gm.collect(self.bases.iter().map(|b| &b.gd))
+    }
+
+    /// Walks from the bases for handles to freed values,
+    /// and counts values nothing reaches
+    pub fn audit(&self) -> Audit {
+        let stats = self.gm.stats();
+        let unreachable = self.gm.unreachable(self.bases.iter().map(|b| &b.gd));
+        let mut seen = vec![false; stats.slots];
+        let mut dangling = Vec::new();
+        let mut stack: Vec<(Proto, &GenData)> = self
+            .bases
+            .iter()
+            .rev()
+            .map(|b| (b.path.clone(), &b.gd))
+            .collect();
+        while let Some((p, gd)) = stack.pop() {
+            let v = match self.gm.get(gd) {
+                Some(v) => v,
+                None => {
+                    dangling.push(p);
+                    continue;
+                }
+            };
+            if seen[gd.slot()] {
+                continue;
+            }
+            seen[gd.slot()] = true;
+            match v {
+                Value::Map(m) => {
+                    for (k, c) in m.iter().rev() {
+                        stack.push((p.clone().push(k.clone()), c));
+                    }
+                }
+                Value::List(l) => {
+                    for (i, c) in l.iter().enumerate().rev() {
+                        stack.push((p.clone().push(ProtoNode::Num(i)), c));
+                    }
+                }
+                Value::Ref(c) => stack.push((p, c)),
+                _ => {}
+            }
+        }
+        Audit {
+            stats,
+            unreachable,
+            dangling,
+        }
+    }
+
+    /// The audit as a value, for the "mem" action
+    fn mem_value(&mut self) -> Value {
+        let a = self.audit();
+        let mut m = BTreeMap::new();
+        m.insert(
+            ProtoNode::str("clean"),
+            self.gm.push(Value::Bool(a.is_clean())),
+        );
+        let nums = [
+            ("slots", a.stats.slots),
+            ("live", a.stats.live),
+            ("free", a.stats.free),
+            ("churn", a.stats.churn as usize),
+            ("unreachable", a.unreachable),
+        ];
+        for (k, n) in nums.iter() {
+            m.insert(ProtoNode::str(k), self.gm.push(Value::Num(*n as isize)));
+        }
+        let dl = a
+            .dangling
+            .iter()
+            .map(|p| self.gm.push(Value::Str(p.to_string())))
+            .collect();
+        m.insert(ProtoNode::str("dangling"), self.gm.push(Value::List(dl)));
+        Value::Map(m)
+    }
+
+    /// Prints the audit asked for by a "mem" action, before any collection hides what leaked
+    fn maybe_report_mem(&mut self) {
+        if self.mem_asked {
+            self.mem_asked = false;
+            let v = self.mem_value();
+            println!("{}", v.print(0, &self.gm));
+            self.gm.drop(v);
+        }
+    }
+
+    /// Collects when asked by a "gc" action, or after enough allocations
+    fn maybe_collect(&mut self) {
+        if self.gc_asked {
+            self.gc_asked = false;
+            let n = self.collect_garbage();
+            println!("Collected {} values", n);
+            return;
+        }
+        if self.gc_every > 0 && self.gm.allocs() >= self.gc_every {
+            self.collect_garbage();
+        }
+    }
+
+    /// Files are all kept, as what they define can still fail long after loading.
+    /// Inputs and strings parsed as paths, named in <>, only need to last a while
+    fn drop_old_inputs(&mut self) {
+        let inputs: Vec<usize> = self
+            .sources
+            .iter()
+            .filter(|(_, s)| s.name.starts_with('<'))
+            .map(|(k, _)| *k)
+            .collect();
+        if inputs.len() > KEEP_INPUTS {
+            for k in &inputs[..inputs.len() - KEEP_INPUTS] {
+                self.sources.remove(k);
+            }
+        }
+    }
+
+    /// The error with the source line and a caret under where it happened
+    pub fn render_err(&self, e: &ActionError) -> String {
+        e.render(&self.sources)
+    }
+
+    /// Shows the dice behind the last action,
+    /// inline if they all came from the resolved expression
+    fn print_rolls(&self, ex: Option<&Expr>) {
+        if self.rolls.len() == 0 {
+            return;
+        }
+        if let Some(ex) = ex {
+            if ex.count_dice() == self.rolls.len() {
+                print!(
+                    "{} = {} = ",
+                    ex.print(),
+                    ex.print_rolls(&mut self.rolls.iter())
+                );
+                return;
+            }
+        }
+        for r in &self.rolls {
+            println!("{} = {} = {}", r.dice, r, r.total());
+        }
+    }
+
+    pub fn rng_mut(&mut self) -> &mut DiceRng {
+        &mut self.rng
+    }
+
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng.reseed(seed);
+    }
+
+    pub fn push_roll(&mut self, r: Roll) {
+        self.rolls.push(r);
+    }
+
+    pub fn gm(&self) -> &GenManager {
+        &self.gm
     }
 
     pub fn gm_mut(&mut self) -> &mut GenManager {
         &mut self.gm
     }
 
+    /// Runs the file in a scope of its own, and puts everything it made at p
+    pub fn load_into<P: AsRef<Path> + Debug>(
+        &mut self,
+        fname: P,
+        p: &Proto,
+    ) -> Result<(), ActionError> {
+        let gd = self.on_wrap(|sc| {
+            sc.run_file(fname)?;
+            let b = sc.bases.iter().rev().find(|b| !b.swap_off);
+            let gd = b.expect("Wrap base should exist").gd.clone_weak();
+            Ok(gd.clone_strong(&mut sc.gm))
+        })?;
+        self.set_gd(p, gd)
+    }
+
     pub fn run_file<P: AsRef<Path> + Debug>(&mut self, fname: P) -> Result<(), ActionError> {
-        let fs = std::fs::read_to_string(&fname).map_err(|e| ActionError::new(&e.to_string()))?;
-        self.handle_input(&fs)
+        let fs = std::fs::read_to_string(&fname).map_err(|e| ActionError::Io(e.to_string()))?;
+        self.run_source(&fname.as_ref().display().to_string(), &fs)
     }
 
     pub fn push_mem(&mut self, v: Value) -> GenData {
         self.gm.push(v)
     }
 
-    pub fn select_base(&self, p: &Proto) -> Option<&GenData> {
-        let n = if p.root {
-            p.dots
-        } else {
-            let mut last = 0;
-            for (i, b) in self.bases.iter().enumerate() {
-                if !b.swap_off {
-                    last = i;
-                }
+    /// Plain paths start at the innermost scope,
+    /// one dot is the innermost selection within it, and each further dot one selection out
+    fn base_index(&self, p: &Proto) -> usize {
+        if p.root {
+            return p.dots;
+        }
+        let mut last = 0;
+        for (i, b) in self.bases.iter().enumerate() {
+            if !b.swap_off {
+                last = i;
             }
-            last + p.dots
-        };
-        self.bases.get(n).map(|v| &v.gd)
+        }
+        match p.dots {
+            0 => last,
+            d if self.bases.len() - last > d => self.bases.len() - d,
+            _ => self.bases.len(),
+        }
+    }
+
+    pub fn select_base(&self, p: &Proto) -> Option<&GenData> {
+        self.bases.get(self.base_index(p)).map(|v| &v.gd)
     }
 
     pub fn get<'a>(&'a self, p: &Proto) -> Option<&'a Value> {
-        let b = self.select_base(p)?.clone_weak();
-        self.get_from(&b, p.pp()).map(|(_, v)| v)
+        self.find(p).map(|(_, v)| v)
     }
 
     pub fn get_ref(&self, p: &Proto) -> Option<GenData> {
-        let b = self.select_base(p)?.clone_weak();
-        self.get_from(&b, p.pp()).map(|(r, _)| r)
+        self.find(p).map(|(r, _)| r)
+    }
+
+    fn find<'a>(&'a self, p: &Proto) -> Option<(GenData, &'a Value)> {
+        self.lookup(p).ok()
+    }
+
+    /// Reads that miss in a wrapped scope fall back to the enclosing scopes,
+    /// so function bodies can still see global data.
+    /// A miss is a LinkCycle if links went round too often to find anything
+    pub fn lookup<'a>(&'a self, p: &Proto) -> Result<(GenData, &'a Value), ActionError> {
+        self.find_linked(p, 0).map_err(|m| match m {
+            Miss::Cycle(l) => ActionError::LinkCycle(l),
+            Miss::Missing => ActionError::PathNotFound(p.clone()),
+        })
+    }
+
+    fn find_linked<'a>(&'a self, p: &Proto, depth: usize) -> Result<(GenData, &'a Value), Miss> {
+        let b = self.select_base(p).ok_or(Miss::Missing)?.clone_weak();
+        let mut miss = match self.get_linked(&b, p.pp(), depth) {
+            Ok(r) => return Ok(r),
+            Err(m) => m,
+        };
+        if !p.root && p.dots == 0 {
+            let outer = self.bases.iter().rev().filter(|b| !b.swap_off).skip(1);
+            for b in outer {
+                match self.get_linked(&b.gd, p.pp(), depth) {
+                    Ok(r) => return Ok(r),
+                    Err(m) => {
+                        if let Miss::Missing = miss {
+                            miss = m;
+                        }
+                    }
+                }
+            }
+        }
+        Err(miss)
     }
 
     pub fn get_from<'a>(&'a self, base: &GenData, pp: ProtoP) -> Option<(GenData, &'a Value)> {
-        let mut v = self.gm.get(base)?;
-        let mut lg = base;
-        while let Value::Ref(g) = v {
+        self.get_linked(base, pp, 0).ok()
+    }
+
+    /// depth counts the links followed to get here, so link cycles end
+    fn get_linked<'a>(
+        &'a self,
+        base: &GenData,
+        pp: ProtoP,
+        depth: usize,
+    ) -> Result<(GenData, &'a Value), Miss> {
+        let bv = self.gm.get(base).ok_or(Miss::Missing)?;
+        let (mut lg, mut v) = self.follow(base.clone_weak(), bv, depth)?;
+        for p in pp {
+            let c = self.child_of(v, p, depth)?;
+            let cv = self.gm.get(c).ok_or(Miss::Missing)?;
+            let (g, cv) = self.follow(c.clone_weak(), cv, depth)?;
             lg = g;
-            v = self.gm.get(g)?;
+            v = cv;
         }
-        for p in pp {
-            lg = v.child_ref(p)?;
-            v = self.gm.get(lg)?;
-            while let Value::Ref(g) = v {
-                lg = g;
-                v = self.gm.get(g)?;
+        Ok((lg, v))
+    }
+
+    /// Follows references and links from a value held at lg.
+    /// A weak reference whose target has been freed reads as Null
+    fn follow<'a>(
+        &'a self,
+        mut lg: GenData,
+        mut v: &'a Value,
+        depth: usize,
+    ) -> Result<(GenData, &'a Value), Miss> {
+        loop {
+            let g = match v {
+                Value::Ref(g) | Value::Weak(g) => g,
+                Value::Link(p) if depth < MAX_LINK_DEPTH => {
+                    return self.find_linked(p, depth + 1);
+                }
+                Value::Link(p) => return Err(Miss::Cycle(p.clone())),
+                _ => return Ok((lg, v)),
+            };
+            v = match (v, self.gm.get(g)) {
+                (_, Some(tv)) => tv,
+                (Value::Weak(_), None) => return Ok((lg, &Value::Null)),
+                _ => return Err(Miss::Missing),
+            };
+            lg = g.clone_weak();
+        }
+    }
+
+    /// Finds a child on the value, or failing that along its proto chain
+    fn child_of<'a>(
+        &'a self,
+        v: &'a Value,
+        p: &ProtoNode,
+        depth: usize,
+    ) -> Result<&'a GenData, Miss> {
+        let mut v = v;
+        for _ in 0..MAX_PROTO_DEPTH {
+            if let Some(c) = v.child_ref(p) {
+                return Ok(c);
             }
+            let pg = v.proto().ok_or(Miss::Missing)?;
+            let pv = self.gm.get(pg).ok_or(Miss::Missing)?;
+            v = self.follow(pg.clone_weak(), pv, depth)?.1;
+        }
+        Err(Miss::Missing)
+    }
+
+    /// The path from the root, through any selections
+    fn absolute(&self, p: &Proto) -> Proto {
+        match self.bases.get(self.base_index(p)) {
+            Some(b) => b.path.extend_new(p.pp()),
+            None => p.clone(),
+        }
+    }
+
+    /// How a link should name p: rooted when p is global data, so it is found from anywhere,
+    /// or as written when p is local, so it is found the way a read here would find it
+    fn link_target(&self, p: &Proto) -> Proto {
+        let i = self.base_index(p);
+        let local = match self.bases.get(i) {
+            Some(b) if b.local && !p.root => p.dots > 0 || self.held_locally(p, i),
+            _ => false,
+        };
+        match local {
+            true => p.clone(),
+            false => self.absolute(p).rooted(),
+        }
+    }
+
+    /// Whether a read of the plain name p stops in a local scope before reaching the root
+    fn held_locally(&self, p: &Proto, i: usize) -> bool {
+        let first = match p.pp().next() {
+            Some(n) => Proto::one(n.clone()),
+            None => return false,
+        };
+        let outer = self.bases[..i].iter().rev().filter(|b| !b.swap_off);
+        std::iter::once(&self.bases[i])
+            .chain(outer)
+            .take_while(|b| b.local)
+            .any(|b| self.get_from(&b.gd, first.pp()).is_some())
+    }
+
+    /// Docs sit beside the field as a Doc node, so they are not read as data
+    pub fn set_doc(&mut self, p: &Proto, doc: &str) -> Result<(), ActionError> {
+        let last = p.last().ok_or(ActionError::EmptyPath)?;
+        let dp = p.parent().push(ProtoNode::Doc(last.as_string()));
+        self.set(&dp, Value::str(doc))
+    }
+
+    /// The doc written above the field, inherited like the field itself
+    pub fn get_doc(&self, p: &Proto) -> Option<&Value> {
+        let last = p.last()?;
+        self.get(&p.parent().push(ProtoNode::Doc(last.as_string())))
+    }
+
+    /// A link to the target as seen from here, as `->` would write it
+    pub fn link_to(&self, target: &Proto) -> Value {
+        Value::Link(self.link_target(target))
+    }
+
+    /// Writes a link at p, which finds whatever is at the target each time it is read.
+    /// The link is written where a plain set would write it
+    pub fn link(&mut self, p: &Proto, target: &Proto) -> Result<(), ActionError> {
+        let t = self.link_target(target);
+        if t.starts_with(&self.link_target(p)) {
+            return Err(ActionError::LinkIntoSelf(p.clone()));
+        }
+        self.set(p, Value::Link(t))
+    }
+
+    /// The value to create when writing below a missing child.
+    /// If the child is inherited, the new map inherits from it, so only what is written is copied
+    fn inherit_child(&mut self, gd: &GenData, p: &ProtoNode) -> Value {
+        let inherited = match self.gm.get(gd) {
+            Some(v) if v.child_ref(p).is_none() => {
+                match self.get_from(gd, Proto::one(p.clone()).pp()) {
+                    Some((g, Value::Map(_))) => Some(g),
+                    _ => None,
+                }
+            }
+            _ => None,
+        };
+        match inherited {
+            Some(g) => {
+                let g = g.to_strong(&mut self.gm);
+                self.instance(g)
+            }
+            None => Value::map(),
+        }
+    }
+
+    /// A new map inheriting from the given (strong) reference
+    pub fn instance(&mut self, gd: GenData) -> Value {
+        let pr = self.gm.push(Value::Ref(gd));
+        let mut m = BTreeMap::new();
+        m.insert(ProtoNode::str("proto"), pr);
+        Value::Map(m)
+    }
+
+    /// Replaces a reference to a Num, Bool or Str with a copy of that value
+    pub fn deref_scalar(&mut self, v: Value) -> Value {
+        let gd = match v {
+            Value::Ref(gd) => gd,
+            v => return v,
+        };
+        let mut tv = self.gm.get(&gd);
+        while let Some(Value::Ref(g)) = tv {
+            tv = self.gm.get(g);
+        }
+        let res = match tv {
+            Some(Value::Num(_)) | Some(Value::Bool(_)) | Some(Value::Str(_)) => {
+                tv.map(|v| v.clone_weak())
+            }
+            _ => None,
+        };
+        match res {
+            Some(r) => {
+                self.gm.drop_ref(gd);
+                r
+            }
+            None => Value::Ref(gd),
         }
-        Some((lg.clone_weak(), v))
     }
 
     pub fn get_or_make_child(
@@ -140,37 +664,165 @@ impl Scope {
             },
             //Value::List(l)=>
             //Ref::
-            _ => Err(ActionError::new(
-                "Cannot give child to Non Map or List Value",
-            )),
+            v => Err(ActionError::wrong_type("map or list", v)),
         }
     }
 
     pub fn set(&mut self, p: &Proto, v: Value) -> Result<(), ActionError> {
         let b = self
             .select_base(p)
-            .ok_or(ActionError::new("No base"))?
+            .ok_or_else(|| ActionError::PathNotFound(p.clone()))?
             .clone_weak();
         self.set_from(b, p.pp(), v)
     }
 
-    pub fn set_from(
+    /// Puts an existing (strong) value at the path, dropping it if that fails
+    pub fn set_gd(&mut self, p: &Proto, gd: GenData) -> Result<(), ActionError> {
+        let b = match self.select_base(p) {
+            Some(b) => b.clone_weak(),
+            None => {
+                self.gm.drop_ref(gd);
+                return Err(ActionError::PathNotFound(p.clone()));
+            }
+        };
+        self.set_gd_from(b, p.pp(), gd)
+    }
+
+    /// The container that actually holds the end of the path (not a proto), and the last node
+    fn holder_of(&self, p: &Proto) -> Result<(GenData, ProtoNode), ActionError> {
+        let nf = || ActionError::PathNotFound(p.clone());
+        let last = p.last().ok_or_else(nf)?.clone();
+        let par = p.parent();
+        let gd = match par.len() {
+            0 => self.select_base(p).map(|g| g.clone_weak()),
+            _ => self.get_ref(&par),
+        }
+        .ok_or_else(nf)?;
+        let gd = self.follow_refs(gd);
+        match self.gm.get(&gd).and_then(|v| v.child_ref(&last)) {
+            Some(_) => Ok((gd, last)),
+            None => Err(nf()),
+        }
+    }
+
+    /// Removes the value at the path, giving back the holder's reference to it
+    fn take(&mut self, p: &Proto) -> Result<GenData, ActionError> {
+        let (gd, last) = self.holder_of(p)?;
+        self.gm
+            .get_mut(&gd)
+            .and_then(|v| v.take_child(&last))
+            .ok_or_else(|| ActionError::PathNotFound(p.clone()))
+    }
+
+    pub fn delete(&mut self, p: &Proto) -> Result<(), ActionError> {
+        let gd = self.take(p)?;
+        self.gm.drop_ref(gd);
+        Ok(())
+    }
+
+    /// Moves the value, anything already at the destination is replaced.
+    /// Nothing changes if either path is bad
+    pub fn move_path(&mut self, from: &Proto, to: &Proto) -> Result<(), ActionError> {
+        if to.starts_with(from) {
+            return Err(ActionError::MoveIntoSelf(from.clone()));
+        }
+        let (h, last) = self.holder_of(from)?;
+        let gd = match self.gm.get(&h).and_then(|v| v.child_ref(&last)) {
+            Some(g) => g.clone_weak(),
+            None => return Err(ActionError::PathNotFound(from.clone())),
+        };
+        let gd = gd.to_strong(&mut self.gm);
+        self.set_gd(to, gd)?;
+        self.delete(from)
+    }
+
+    /// Deep copies the value at p to p1..pn beside it, so each has its own state.
+    /// Protos are still shared, but weak refs and links inside the copy point into the copy.
+    /// Like rename, it will not replace anything already at p1..pn
+    pub fn spawn(&mut self, p: &Proto, n: isize) -> Result<(), ActionError> {
+        if n < 1 || n as usize > MAX_SPAWN {
+            return Err(ActionError::SpawnCount {
+                max: MAX_SPAWN,
+                got: n,
+            });
+        }
+        let name = match p.last() {
+            Some(ProtoNode::Str(s)) => s.clone(),
+            _ => return Err(ActionError::NotNamed(p.clone())),
+        };
+        let gd = self
+            .get_ref(p)
+            .ok_or_else(|| ActionError::PathNotFound(p.clone()))?;
+        let targets: Vec<Proto> = (1..=n)
+            .map(|i| p.parent().push(ProtoNode::Str(format!("{}{}", name, i))))
+            .collect();
+        if let Some(t) = targets.iter().find(|t| self.holder_of(t).is_ok()) {
+            return Err(ActionError::AlreadyExists(t.clone()));
+        }
+        let from = self.link_target(p);
+        for to in targets {
+            let to_l = match from.root {
+                true => self.absolute(&to).rooted(),
+                false => to.clone(),
+            };
+            let c = self
+                .gm
+                .deep_clone_with(&gd, false, &|l| l.rebase(&from, &to_l))
+                .ok_or(ActionError::FreedReference)?;
+            self.set_gd(&to, c)?;
+        }
+        Ok(())
+    }
+
+    pub fn rename(&mut self, p: &Proto, name: &str) -> Result<(), ActionError> {
+        let to = p.parent().push(ProtoNode::str(name));
+        if self.holder_of(&to).is_ok() {
+            return Err(ActionError::AlreadyExists(to));
+        }
+        self.move_path(p, &to)
+    }
+
+    /// Follows a chain of references to the value they end at, so writes go through them
+    fn follow_refs(&self, mut gd: GenData) -> GenData {
+        loop {
+            gd = match self.gm.get(&gd) {
+                Some(Value::Ref(g)) => g.clone_weak(),
+                Some(Value::Weak(g)) if self.gm.is_live(g) => g.clone_weak(),
+                Some(Value::Link(p)) => match self.find_linked(p, 0) {
+                    Ok((g, _)) => g,
+                    Err(_) => return gd,
+                },
+                _ => return gd,
+            }
+        }
+    }
+
+    pub fn set_from(&mut self, c_gd: GenData, pp: ProtoP, nval: Value) -> Result<(), ActionError> {
+        let val_ref = self.gm.push(nval);
+        self.set_gd_from(c_gd, pp, val_ref)
+    }
+
+    fn set_gd_from(
         &mut self,
         mut c_gd: GenData,
         mut pp: ProtoP,
-        nval: Value,
+        val_ref: GenData,
     ) -> Result<(), ActionError> {
         if pp.remaining() == 0 {
-            return Err(ActionError::new("empty path"));
+            self.gm.drop_ref(val_ref);
+            return Err(ActionError::EmptyPath);
         }
         while pp.remaining() > 1 {
             let p = pp.next().unwrap();
-            let n_gd = self.gm.push(Value::map());
+            c_gd = self.follow_refs(c_gd);
+            let nv = self.inherit_child(&c_gd, p);
+            let n_gd = self.gm.push(nv);
             let v = match self.gm.get_mut(&c_gd) {
                 Some(v) => v,
                 None => {
                     self.gm.drop_ref(n_gd);
-                    return Err(ActionError::new("no base or deeper problem"))?;
+                    self.gm.drop_ref(val_ref);
+                    return Err(ActionError::FreedReference);
                 }
             };
             let n_drop = n_gd.clone_ig();
@@ -182,23 +834,29 @@ impl Scope {
                 }
                 Err(e) => {
                     self.gm.drop_ref(n_drop);
+                    self.gm.drop_ref(val_ref);
                     return Err(e);
                 }
             };
         }
-        let val_ref = self.gm.push(nval);
-        let v = self
-            .gm
-            .get_mut(&c_gd)
-            .ok_or(ActionError::new("no base or deeper problem"))?;
-        match v.give_child(pp.next().unwrap().clone(), val_ref.clone_weak()) {
+        c_gd = self.follow_refs(c_gd);
+        let v = match self.gm.get_mut(&c_gd) {
+            Some(v) => v,
+            None => {
+                self.gm.drop_ref(val_ref);
+                return Err(ActionError::FreedReference);
+            }
+        };
+        //The map keeps the strong reference, so dropping the field releases the value
+        let drop = val_ref.clone_ig();
+        match v.give_child(pp.next().unwrap().clone(), val_ref) {
             Ok(Some(gdrop)) => {
                 self.gm.drop_ref(gdrop);
                 Ok(())
             }
             Ok(None) => Ok(()),
             Err(e) => {
-                self.gm.drop_ref(val_ref);
+                self.gm.drop_ref(drop);
                 Err(e)
             }
         }
@@ -208,68 +866,396 @@ impl Scope {
         self.on_wrap(|sc| ex.eval(sc))
     }
 
+    /// Calls func with (k,v) for each item, or (acc,k,v) when folding,
+    /// where acc is the result of the previous call
     pub fn for_each<T, IT>(
         &mut self,
-        _it: IT,
-        _fold: Option<Value>,
-        _func: Value,
+        mut it: IT,
+        fold: Option<Value>,
+        func: Value,
     ) -> Result<Option<Value>, ActionError>
     where
         Value: From<T>,
         IT: Iterator<Item = (T, Value)>,
     {
-        unimplemented!()
+        let folding = fold.is_some();
+        let mut acc = fold;
+        while let Some((k, v)) = it.next() {
+            let mut params = Vec::new();
+            if let Some(a) = acc.take() {
+                params.push(a);
+            }
+            params.push(Value::from(k));
+            params.push(v);
+            match self.call_func(func.clone_weak(), None, params) {
+                Ok(r) if folding => acc = Some(r),
+                Ok(r) => self.gm.drop(r),
+                Err(e) => {
+                    for (_, v) in it {
+                        self.gm.drop(v);
+                    }
+                    self.gm.drop(func);
+                    return Err(e);
+                }
+            }
+        }
+        self.gm.drop(func);
+        Ok(acc)
+    }
+
+    /// The (key, value) pairs a loop walks over.
+    /// List and map items are given as references, so loops can change them
+    pub fn loop_items(&mut self, v: Value) -> Result<Vec<(Value, Value)>, ActionError> {
+        match v {
+            Value::Num(n) => Ok(num_range(0, n, false)?
+                .map(|i| (Value::Num(i), Value::Num(i)))
+                .collect()),
+            Value::List(l) => Ok(l
+                .into_iter()
+                .enumerate()
+                .map(|(i, gd)| (Value::Num(i as isize), Value::Ref(gd)))
+                .collect()),
+            Value::Map(m) => {
+                let mut res = Vec::new();
+                for (k, gd) in m {
+                    match k.is_meta() {
+                        true => self.gm.drop_ref(gd),
+                        false => res.push((Value::Str(k.as_string()), Value::Ref(gd))),
+                    }
+                }
+                Ok(res)
+            }
+            Value::Ref(gd) | Value::Weak(gd) => {
+                let inner = match self.gm.get(&gd) {
+                    Some(v) => v.clone_weak(),
+                    None => Value::Null,
+                };
+                let inner = inner.to_strong(&mut self.gm);
+                self.gm.drop_ref(gd);
+                self.loop_items(inner)
+            }
+            v => {
+                let e = ActionError::wrong_type("num, list or map", &v);
+                self.gm.drop(v);
+                Err(e)
+            }
+        }
+    }
+
+    /// Binds a single name to the value, or two names to the key and value
+    fn bind_loop_vars(&mut self, names: &[String], k: Value, v: Value) -> Result<(), ActionError> {
+        match names {
+            [vn] => {
+                self.gm.drop(k);
+                self.set(&Proto::str(vn), v)
+            }
+            [kn, vn] => {
+                self.set(&Proto::str(kn), k)?;
+                self.set(&Proto::str(vn), v)
+            }
+            _ => {
+                self.gm.drop(k);
+                self.gm.drop(v);
+                Err(ActionError::LoopNames(names.len()))
+            }
+        }
+    }
+
+    pub fn run_for(
+        &mut self,
+        names: &[String],
+        items: Vec<(Value, Value)>,
+        block: &[Action],
+    ) -> AcResult {
+        let mut it = items.into_iter();
+        let res = self.for_items(names, &mut it, block);
+        for (k, v) in it {
+            self.gm.drop(k);
+            self.gm.drop(v);
+        }
+        res
+    }
+
+    fn for_items(
+        &mut self,
+        names: &[String],
+        it: &mut std::vec::IntoIter<(Value, Value)>,
+        block: &[Action],
+    ) -> AcResult {
+        for (k, v) in it {
+            self.bind_loop_vars(names, k, v)?;
+            match self.do_actions(block)? {
+                (AcReturn::Func, r) => return Ok((AcReturn::Func, r)),
+                (AcReturn::Break, _) => break,
+                (_, r) => self.gm.drop(r),
+            }
+        }
+        Ok((AcReturn::No, Value::Null))
+    }
+
+    pub fn run_while(&mut self, cond: &Expr, block: &[Action]) -> AcResult {
+        for _ in 0..MAX_WHILE {
+            let c = cond.eval(self)?;
+            match self.deref_scalar(c) {
+                Value::Bool(true) => {}
+                Value::Bool(false) => return Ok((AcReturn::No, Value::Null)),
+                v => {
+                    let e = ActionError::wrong_type("bool", &v);
+                    self.gm.drop(v);
+                    return Err(e);
+                }
+            }
+            match self.do_actions(block)? {
+                (AcReturn::Func, r) => return Ok((AcReturn::Func, r)),
+                (AcReturn::Break, _) => return Ok((AcReturn::No, Value::Null)),
+                (_, r) => self.gm.drop(r),
+            }
+        }
+        Err(ActionError::LoopLimit(MAX_WHILE))
+    }
+
+    /// The map a path was found on and its parent, for binding "self"
+    pub fn owner_of(&self, p: &Proto) -> Option<Owner> {
+        let this = self.get_ref(&p.parent())?;
+        let parent = match p.len() {
+            0 | 1 => None,
+            _ => self.get_ref(&p.parent().parent()),
+        };
+        Some(Owner { this, parent })
+    }
+
+    /// Builtins come first, so data can't hide them by sharing a name
+    pub fn call_path(&mut self, p: &Proto, params: Vec<Value>) -> Result<Value, ActionError> {
+        let res = match p.as_api_func_name() {
+            Some(name) => api_funcs::run_api_func(name, self, &params),
+            None => None,
+        };
+        if let Some(r) = res {
+            for pv in params {
+                self.gm.drop(pv);
+            }
+            return r.map(|v| v.unwrap_or(Value::Null));
+        }
+        let fv = match self.get(p) {
+            Some(v) => v.clone_weak(),
+            None => {
+                for pv in params {
+                    self.gm.drop(pv);
+                }
+                return Err(ActionError::PathNotFound(p.clone()));
+            }
+        };
+        let owner = self.owner_of(p);
+        self.call_func(fv, owner, params)
+    }
+
+    pub fn call_func(
+        &mut self,
+        f: Value,
+        owner: Option<Owner>,
+        params: Vec<Value>,
+    ) -> Result<Value, ActionError> {
+        match f {
+            Value::ExprDef(pnames, ex) => self.on_wrap(|sc| {
+                sc.bind_owner(owner)?;
+                sc.bind_params(&pnames, params)?;
+                ex.eval(sc)
+            }),
+            Value::FuncDef(pnames, actions) => self.run_func(&pnames, &actions, owner, params),
+            v => {
+                let e = ActionError::NotCallable(v.type_name());
+                self.gm.drop(v);
+                for p in params {
+                    self.gm.drop(p);
+                }
+                Err(e)
+            }
+        }
     }
 
     pub fn run_func(
         &mut self,
         pnames: &[String],
         actions: &[Action],
+        owner: Option<Owner>,
         params: Vec<Value>,
     ) -> Result<Value, ActionError> {
         self.on_wrap(|sc| {
-            for p in 0..params.len() {
-                if pnames.len() > p {
-                    //TODO Set parameters
-                }
-            }
-            sc.do_actions(actions).map(|(_, v)| v)
+            sc.bind_owner(owner)?;
+            sc.bind_params(pnames, params)?;
+            outside_loop(sc.do_actions(actions))
         })
     }
 
+    /// Sets "self" and "parent" in the current wrapped scope
+    pub fn bind_owner(&mut self, owner: Option<Owner>) -> Result<(), ActionError> {
+        let owner = match owner {
+            Some(o) => o,
+            None => return Ok(()),
+        };
+        let this = owner.this.to_strong(&mut self.gm);
+        self.set(&Proto::str("self"), Value::Ref(this))?;
+        if let Some(p) = owner.parent {
+            let p = p.to_strong(&mut self.gm);
+            self.set(&Proto::str("parent"), Value::Ref(p))?;
+        }
+        Ok(())
+    }
+
+    /// Sets each named parameter in the current wrapped scope,
+    /// missing params are Null
+    pub fn bind_params(
+        &mut self,
+        pnames: &[String],
+        params: Vec<Value>,
+    ) -> Result<(), ActionError> {
+        if params.len() > pnames.len() {
+            let e = ActionError::Arity {
+                expected: pnames.len(),
+                got: params.len(),
+            };
+            for p in params {
+                self.gm.drop(p);
+            }
+            return Err(e);
+        }
+        let mut it = params.into_iter();
+        for pn in pnames {
+            let v = it.next().unwrap_or(Value::Null);
+            self.set(&Proto::str(pn), v)?;
+        }
+        Ok(())
+    }
+
+    /// `Name:` replaces the selection, `.child:` selects deeper within it.
+    /// Selecting a path with nothing there creates an empty map to fill in
     pub fn colon_select(&mut self, p: &Proto) -> Result<Value, ActionError> {
+        if self.get_ref(&p).is_none() {
+            self.set(&p, Value::map())?;
+        }
         let r = self
             .get_ref(&p)
-            .ok_or(ActionError::new("Could not set path to non path"))?;
+            .ok_or_else(|| ActionError::PathNotFound(p.clone()))?;
+        let from = &self.bases[self.base_index(p)];
+        let (path, local) = (from.path.extend_new(p.pp()), from.local);
         let rc = r.clone_weak();
         self.gm.inc_rc(&rc);
-        let nb = Base {
+        if p.dots == 0 {
+            self.clear_selection();
+        }
+        self.bases.push(Base {
             gd: rc,
             swap_off: true,
-        };
-        let blast = self.bases.len() - 1;
-        match self.bases[blast].swap_off {
-            true => self.bases[blast] = nb,
-            false => self.bases.push(nb),
-        }
+            path,
+            local,
+        });
         Ok(Value::Null)
     }
 
-    //return bool is for is return
+    /// Drops all selections back to the enclosing scope
+    pub fn clear_selection(&mut self) {
+        while let Some(true) = self.bases.last().map(|b| b.swap_off) {
+            let b = self.bases.pop().unwrap();
+            self.gm.drop_ref(b.gd);
+        }
+    }
+
+    pub fn pop_selection(&mut self) -> Result<(), ActionError> {
+        match self.bases.last() {
+            Some(b) if b.swap_off => {
+                let b = self.bases.pop().unwrap();
+                self.gm.drop_ref(b.gd);
+                Ok(())
+            }
+            _ => Err(ActionError::NothingSelected),
+        }
+    }
+
+    /// The path of the innermost selection, empty at the top level
+    pub fn selection(&self) -> Proto {
+        match self.bases.last() {
+            Some(b) if b.swap_off => b.path.clone(),
+            _ => Proto::new(),
+        }
+    }
+
+    pub fn mark(&mut self, name: &str) {
+        self.marks.insert(name.to_string(), self.selection());
+    }
+
+    pub fn goto_mark(&mut self, name: &str) -> Result<(), ActionError> {
+        let p = self
+            .marks
+            .get(name)
+            .ok_or_else(|| ActionError::NoBookmark(name.to_string()))?
+            .clone();
+        if p.len() == 0 {
+            self.clear_selection();
+            return Ok(());
+        }
+        self.get_ref(&p)
+            .ok_or_else(|| ActionError::PathNotFound(p.clone()))?;
+        self.colon_select(&p).map(|_| ())
+    }
+
+    /// Strong copies of the selections above the enclosing scope
+    fn save_selection(&mut self) -> Vec<Base> {
+        let mut res = Vec::new();
+        for b in self.bases.iter().rev().take_while(|b| b.swap_off) {
+            res.push(Base {
+                gd: b.gd.clone_strong(&mut self.gm),
+                swap_off: true,
+                path: b.path.clone(),
+                local: b.local,
+            });
+        }
+        res.reverse();
+        res
+    }
+
+    /// Runs the block with the head's selection,
+    /// then puts back whatever was selected before
+    fn select_block(&mut self, head: &Action, body: &[Action]) -> AcResult {
+        let saved = self.save_selection();
+        let res = match self.do_action(head) {
+            Ok(v) => {
+                self.gm.drop(v);
+                self.do_actions(body)
+            }
+            Err(e) => Err(e),
+        };
+        self.clear_selection();
+        self.bases.extend(saved);
+        res
+    }
+
+    /// The AcReturn says if a return, break or continue is passing out of the block,
+    /// otherwise the value is that of the last action
     pub fn do_actions(&mut self, actions: &[Action]) -> AcResult {
         let mut last_res = Value::Null;
         for a in actions {
-            let r = self.do_action(a)?;
-            if let Action::Resolve(_) = a {
-                return Ok((AcReturn::No, r));
-            }
-            if let Action::Return(_) = a {
-                return Ok((AcReturn::Func, r));
+            let (ret, r) = self.do_block_action(a)?;
+            self.gm.drop(last_res);
+            match ret {
+                AcReturn::No => last_res = r,
+                _ => return Ok((ret, r)),
             }
-            last_res = r;
         }
         Ok((AcReturn::No, last_res))
     }
+
+    fn do_block_action(&mut self, a: &Action) -> AcResult {
+        match a {
+            Action::Return(ex) => Ok((AcReturn::Func, ex.eval(self)?)),
+            Action::Resolve(ex) => ex.eval_action(self),
+            Action::Break => Ok((AcReturn::Break, Value::Null)),
+            Action::Continue => Ok((AcReturn::Continue, Value::Null)),
+            Action::At(sp, a) => self.do_block_action(a).map_err(|e| e.at(*sp)),
+            Action::SelectBlock(head, body) => self.select_block(head, body),
+            a => self.do_action(a).map(|v| (AcReturn::No, v)),
+        }
+    }
+
     pub fn do_action(&mut self, a: &Action) -> Result<Value, ActionError> {
         match a {
             Action::Set(p_ex, v_ex) => {
@@ -277,6 +1263,16 @@ impl Scope {
                 let v = v_ex.eval(self)?;
                 self.set(&p, v).map(|_| Value::Null)
             }
+            Action::Spawn(n_ex, p_ex) => {
+                let n = n_ex.eval_num(self)?;
+                let p = p_ex.eval_path(self)?;
+                self.spawn(&p, n).map(|_| Value::Null)
+            }
+            Action::Link(p_ex, t_ex) => {
+                let p = p_ex.eval_path(self)?;
+                let t = t_ex.eval_path(self)?;
+                self.link(&p, &t).map(|_| Value::Null)
+            }
             Action::Resolve(p_ex) | Action::Return(p_ex) => {
                 let v = p_ex.eval(self)?;
                 //println!(" - {}", v.print(0, &self.gm));
@@ -286,6 +1282,50 @@ impl Scope {
                 let p = p_ex.eval_path(self)?;
                 self.colon_select(&p)
             }
+            Action::SelectBlock(head, body) => outside_loop(self.select_block(head, body)),
+            Action::Pop => self.pop_selection().map(|_| Value::Null),
+            //Values may be held mid expression, so wait for the end of the top level action
+            Action::Gc => {
+                self.gc_asked = true;
+                Ok(Value::Null)
+            }
+            Action::GcEvery(ex) => match ex.eval(self)? {
+                Value::Num(n) if n >= 0 => {
+                    self.set_gc_every(n as usize);
+                    Ok(Value::Null)
+                }
+                v => {
+                    let e = ActionError::wrong_type("non-negative num", &v);
+                    self.gm.drop(v);
+                    Err(e)
+                }
+            },
+            //Like gc, so the statement's own temporaries are not counted as leaks
+            Action::Mem => {
+                self.mem_asked = true;
+                Ok(Value::Null)
+            }
+            Action::SelectRoot => {
+                self.clear_selection();
+                Ok(Value::Null)
+            }
+            Action::Mark(name) => {
+                self.mark(name);
+                Ok(Value::Null)
+            }
+            Action::GotoMark(name) => self.goto_mark(name).map(|_| Value::Null),
+            Action::Delete(p_ex) => {
+                let p = p_ex.eval_path(self)?;
+                self.delete(&p).map(|_| Value::Null)
+            }
+            Action::Move(a, b) => {
+                let (pa, pb) = (a.eval_path(self)?, b.eval_path(self)?);
+                self.move_path(&pa, &pb).map(|_| Value::Null)
+            }
+            Action::Rename(p_ex, name) => {
+                let p = p_ex.eval_path(self)?;
+                self.rename(&p, name).map(|_| Value::Null)
+            }
             Action::SetSelect(p_ex, v_ex) => {
                 let p = p_ex.eval_path(self)?;
                 let v = v_ex.eval(self)?;
@@ -299,14 +1339,14 @@ impl Scope {
                         Some(gd) => Some(gd.clone_ig()),
                         None => None,
                     },
-                    _ => return Err(ActionError::new("cannot add like this to non map")),
+                    Some(v) => return Err(ActionError::wrong_type("map", v)),
+                    None => return Err(ActionError::FreedReference),
                 };
                 match gdo {
                     Some(gd) => match self.gm.get_mut(&gd) {
                         Some(Value::Num(ref mut n)) => *n += num,
-                        Some(_) | None => {
-                            return Err(ActionError::new("Can only add to number values"))
-                        }
+                        Some(v) => return Err(ActionError::wrong_type("num", v)),
+                        None => return Err(ActionError::FreedReference),
                     },
                     None => {
                         let gd = self.gm.push(Value::Num(*num));
@@ -314,11 +1354,7 @@ impl Scope {
                             Some(Value::Map(m)) => {
                                 m.insert(ProtoNode::str(id), gd);
                             }
-                            _ => {
-                                return Err(ActionError::new(
-                                    "Map  dropped between previous checks",
-                                ))
-                            }
+                            _ => return Err(ActionError::FreedReference),
                         }
                     }
                 }
@@ -328,7 +1364,32 @@ impl Scope {
                 let p = lf.eval_path(self)?;
                 let v = op.eval(lf, rt, self)?;
                 self.set(&p, v).map(|_| Value::Null)
-            } //_ => unimplemented!(),
+            }
+            Action::Doc(d, a) => {
+                let p = match &**a {
+                    Action::Set(p_ex, _) | Action::OpSet(_, p_ex, _) | Action::Link(p_ex, _) => {
+                        p_ex.eval_path(self)?
+                    }
+                    a => return self.do_action(a),
+                };
+                let v = self.do_action(a)?;
+                self.set_doc(&p, d)?;
+                Ok(v)
+            }
+            Action::Break | Action::Continue => Err(ActionError::NotInLoop),
+            Action::At(sp, a) => self.do_action(a).map_err(|e| e.at(*sp)),
+            Action::Seed(None) => Ok(Value::Num(self.rng.seed() as isize)),
+            Action::Seed(Some(ex)) => match ex.eval(self)? {
+                Value::Num(n) if n >= 0 => {
+                    self.set_seed(n as u64);
+                    Ok(Value::Null)
+                }
+                v => {
+                    let e = ActionError::wrong_type("non-negative num", &v);
+                    self.gm.drop(v);
+                    Err(e)
+                }
+            }, //_ => unimplemented!(),
         }
         /*Select(Expr),
         OpSet(Op, Expr, Expr),
@@ -338,3 +1399,428 @@ impl Scope {
         */
     }
 }
+
+#[cfg(test)]
+mod test_scope {
+    use super::*;
+
+    fn path(s: &str) -> Proto {
+        let mut res = Proto::new();
+        for n in s.split('.') {
+            res = match n.parse::<usize>() {
+                Ok(i) => res.push(ProtoNode::Num(i)),
+                Err(_) => res.push(ProtoNode::str(n)),
+            };
+        }
+        res
+    }
+
+    #[test]
+    fn test_proto_inherit() {
+        let mut sc = Scope::new();
+        sc.handle_input("Weapon = {Dam:3,Acc:1}\nDagger = new Weapon\nDagger.Acc = 4\n")
+            .unwrap();
+        assert_eq!(sc.get(&path("Dagger.Dam")), Some(&Value::Num(3)));
+        assert_eq!(sc.get(&path("Dagger.Acc")), Some(&Value::Num(4)));
+        assert_eq!(sc.get(&path("Weapon.Acc")), Some(&Value::Num(1)));
+
+        //$ is still a shared reference
+        sc.handle_input("Sword = $Weapon\nSword.Acc = 2\n").unwrap();
+        assert_eq!(sc.get(&path("Weapon.Acc")), Some(&Value::Num(2)));
+        assert_eq!(sc.get(&path("Dagger.Acc")), Some(&Value::Num(4)));
+    }
+
+    #[test]
+    fn test_loops() {
+        let mut sc = Scope::new();
+        sc.handle_input(
+            "Rats = [{HP:5},{HP:3}]\nfor r in Rats { r.HP = r.HP - 2 }\nt = foreach(3,fn(k,v){ v })\n",
+        )
+        .unwrap();
+        sc.handle_input("n = 0\nfor i in 0..10 { if i == 4 { break }\n :n = :n + i }\n")
+            .unwrap();
+        assert_eq!(sc.get(&path("Rats.0.HP")), Some(&Value::Num(3)));
+        assert_eq!(sc.get(&path("Rats.1.HP")), Some(&Value::Num(1)));
+        assert_eq!(sc.get(&path("n")), Some(&Value::Num(6)));
+
+        let f: Expr = "fn(){ n = 1\nbreak }".parse().unwrap();
+        let f = f.eval(&mut sc).unwrap();
+        assert_eq!(
+            sc.call_func(f, None, Vec::new()),
+            Err(ActionError::NotInLoop)
+        );
+        let e: Expr = "if true { continue }".parse().unwrap();
+        let r = e.eval(&mut sc).map_err(|e| e.kind().clone());
+        assert_eq!(r, Err(ActionError::NotInLoop));
+    }
+
+    #[test]
+    fn test_load_link() {
+        let f = std::env::temp_dir().join("rpg_tracker_load_test.dd");
+        std::fs::write(&f, "Rat = {HP:5}\nn = 2\n").unwrap();
+        let mut sc = Scope::new();
+        sc.handle_input(&format!(
+            "load(\"{}\", \"Bestiary\")\nr = link(\"Bestiary.Rat\")\n",
+            f.display()
+        ))
+        .unwrap();
+        assert_eq!(sc.get(&path("Bestiary.Rat.HP")), Some(&Value::Num(5)));
+        assert_eq!(sc.get(&path("Bestiary.n")), Some(&Value::Num(2)));
+        assert_eq!(sc.get(&path("Rat")), None);
+        assert_eq!(sc.get(&path("r.HP")), Some(&Value::Num(5)));
+
+        //a bad path string is pointed at, not whatever was parsed last
+        let e = api_funcs::link(&mut sc, &[Value::str("= x")]).unwrap_err();
+        let r = sc.render_err(&e);
+        assert!(r.contains("<path>:1:"), "{}", r);
+        assert!(r.contains(" 1 | = x\n"), "{}", r);
+    }
+
+    #[test]
+    fn test_self_binding() {
+        let mut sc = Scope::new();
+        sc.handle_input(
+            "Enemy = {HP:10,Dam:0,Health:expr($self.HP - $self.Dam)}\nRat = new Enemy\nRat.HP = 5\nRat.Dam = 2\nh = Rat.Health\n",
+        )
+        .unwrap();
+        assert_eq!(sc.get(&path("h")), Some(&Value::Num(3)));
+    }
+
+    #[test]
+    fn test_parse_recovery() {
+        let mut sc = Scope::new();
+        let r = sc.run_source(
+            "t.dd",
+            "a = 1\nb = = 2\nc = 3\nd = {\n  e = 1 +\n}\nf = \"x;\"\ng = 4\n",
+        );
+        match r {
+            Err(ActionError::ParseErrs(_, n, errs)) => {
+                assert_eq!(n, "t.dd");
+                assert_eq!(errs.len(), 2);
+            }
+            e => panic!("expected 2 parse errors, got {:?}", e),
+        }
+        assert_eq!(sc.get(&path("a")), Some(&Value::Num(1)));
+        assert_eq!(sc.get(&path("c")), Some(&Value::Num(3)));
+        assert_eq!(sc.get(&path("f")), Some(&Value::str("x;")));
+        assert_eq!(sc.get(&path("g")), Some(&Value::Num(4)));
+        assert_eq!(sc.get(&path("d")), None);
+
+        let mut sc = Scope::new();
+        sc.set_strict(true);
+        assert!(sc.run_source("t.dd", "a = 1\nb = = 2\n").is_err());
+        assert_eq!(sc.get(&path("a")), None);
+
+        for _ in 0..KEEP_INPUTS + 10 {
+            sc.handle_input("x = 1\n").unwrap();
+        }
+        assert_eq!(sc.sources.len(), KEEP_INPUTS + 1);
+    }
+
+    #[test]
+    fn test_comments() {
+        let mut sc = Scope::new();
+        sc.handle_input(
+            "# a comment line\nRat = {\n  ## hit points\n  HP:5, # trailing\n  Speed:/* inline */3\n}\n\
+             l = [1, # one\n 2]\nf = fn(){ # in a block\n 4 }\nn = f() /* after */\n",
+        )
+        .unwrap();
+        assert_eq!(sc.get(&path("Rat.HP")), Some(&Value::Num(5)));
+        assert_eq!(sc.get(&path("Rat.Speed")), Some(&Value::Num(3)));
+        assert_eq!(sc.get_doc(&path("Rat.HP")), Some(&Value::str("hit points")));
+        assert_eq!(sc.get_doc(&path("Rat.Speed")), None);
+        assert_eq!(sc.get(&path("l.1")), Some(&Value::Num(2)));
+        assert_eq!(sc.get(&path("n")), Some(&Value::Num(4)));
+
+        sc.handle_input(
+            "Rat:\n  ## how fast\n  .Speed = 4\nBob = {HP:1}\nBob: {\n  ## health\n  .HP = 2\n}\n\
+             n = len(Rat)\nc = 0\nfor k, v in Rat { :c = :c + 1 }\nsame = Rat == {HP:5, Speed:4}\n",
+        )
+        .unwrap();
+        assert_eq!(
+            sc.get_doc(&path("Rat.Speed")),
+            Some(&Value::str("how fast"))
+        );
+        assert_eq!(sc.get_doc(&path("Bob.HP")), Some(&Value::str("health")));
+        assert_eq!(sc.get(&path("Bob.HP")), Some(&Value::Num(2)));
+        //docs are not data
+        assert_eq!(sc.get(&path("n")), Some(&Value::Num(2)));
+        assert_eq!(sc.get(&path("c")), Some(&Value::Num(2)));
+        assert_eq!(sc.get(&path("same")), Some(&Value::Bool(true)));
+
+        //a field called doc is just data, and can have a doc of its own
+        sc.handle_input(
+            "Bill = {doc: \"Dr. Smith\", HP: 3}\nBill:\n  ## who heals him\n  .doc = \"Dr. Jones\"\n\
+             n = len(Bill)\nd = doc(\"Bill.doc\")\nk = doc(\"Rat.HP\")\n",
+        )
+        .unwrap();
+        assert_eq!(sc.get(&path("Bill.doc")), Some(&Value::str("Dr. Jones")));
+        assert_eq!(sc.get(&path("n")), Some(&Value::Num(2)));
+        assert_eq!(sc.get(&path("d")), Some(&Value::str("who heals him")));
+        assert_eq!(sc.get(&path("k")), Some(&Value::str("hit points")));
+    }
+
+    #[test]
+    fn test_select_blocks() {
+        let mut sc = Scope::new();
+        sc.handle_input(
+            "Player:\n    .HP=10\n    .items:\n        .Gold=3\n\n    .Dam=2\nRat: { .HP=5 }\nx = 4\n",
+        )
+        .unwrap();
+        assert_eq!(sc.get(&path("Player.HP")), Some(&Value::Num(10)));
+        assert_eq!(sc.get(&path("Player.items.Gold")), Some(&Value::Num(3)));
+        assert_eq!(sc.get(&path("Player.Dam")), Some(&Value::Num(2)));
+        assert_eq!(sc.get(&path("Player.items.Dam")), None);
+        assert_eq!(sc.get(&path("Rat.HP")), Some(&Value::Num(5)));
+        assert_eq!(sc.get(&path("x")), Some(&Value::Num(4)));
+        assert_eq!(sc.bases.len(), 1);
+    }
+
+    #[test]
+    fn test_selection_stack() {
+        let mut sc = Scope::new();
+        sc.handle_input("Bill:\n.items:\n.Gold = 3\nmark bag\npop\n.HP = 4\n")
+            .unwrap();
+        assert_eq!(sc.selection().to_string(), "Bill");
+        assert_eq!(sc.get(&path("Bill.HP")), Some(&Value::Num(4)));
+        assert_eq!(sc.get(&path("Bill.items.Gold")), Some(&Value::Num(3)));
+
+        sc.handle_input("root\n").unwrap();
+        assert_eq!(sc.selection().to_string(), "");
+        sc.handle_input("goto bag\n.Gold = 5\n").unwrap();
+        assert_eq!(sc.selection().to_string(), "Bill.items");
+        assert_eq!(sc.get(&path("Bill.items.Gold")), Some(&Value::Num(5)));
+        assert_eq!(sc.bases.len(), 2);
+
+        sc.clear_selection();
+        assert_eq!(sc.pop_selection(), Err(ActionError::NothingSelected));
+        assert_eq!(
+            sc.goto_mark("cave"),
+            Err(ActionError::NoBookmark("cave".to_string()))
+        );
+        let names = vec!["a".to_string(), "b".to_string(), "c".to_string()];
+        let r = sc.run_for(&names, vec![(Value::Num(0), Value::Num(1))], &[]);
+        assert_eq!(r.map(|_| ()), Err(ActionError::LoopNames(3)));
+    }
+
+    #[test]
+    fn test_keyword_names() {
+        let mut sc = Scope::new();
+        sc.handle_input("seed = 5\nspawn = 1\npop = 2\nmv = seed + spawn\n")
+            .unwrap();
+        assert_eq!(sc.get(&path("seed")), Some(&Value::Num(5)));
+        assert_eq!(sc.get(&path("spawn")), Some(&Value::Num(1)));
+        assert_eq!(sc.get(&path("pop")), Some(&Value::Num(2)));
+        assert_eq!(sc.get(&path("mv")), Some(&Value::Num(6)));
+        sc.handle_input("if true { seed 4 }\n").unwrap();
+        assert_eq!(sc.rng_mut().seed(), 4);
+    }
+
+    #[test]
+    fn test_del_mv_rename() {
+        let mut sc = Scope::new();
+        sc.handle_input("River = {items:{Flowers:3,Rocks:[1,2]}}\nBill = {items:{}}\n")
+            .unwrap();
+        let live = sc.gm.live();
+        sc.handle_input("mv River.items.Flowers Bill.items.Flowers\n")
+            .unwrap();
+        assert_eq!(sc.get(&path("River.items.Flowers")), None);
+        assert_eq!(sc.get(&path("Bill.items.Flowers")), Some(&Value::Num(3)));
+        assert_eq!(sc.gm.live(), live);
+
+        sc.handle_input("rename Bill.items.Flowers Roses\n")
+            .unwrap();
+        assert_eq!(sc.get(&path("Bill.items.Roses")), Some(&Value::Num(3)));
+        assert_eq!(sc.gm.live(), live);
+
+        sc.handle_input("del River.items.Rocks\n").unwrap();
+        assert_eq!(sc.get(&path("River.items.Rocks")), None);
+        assert_eq!(sc.gm.live(), live - 3);
+
+        let p = path("River.nothing");
+        assert_eq!(sc.delete(&p), Err(ActionError::PathNotFound(p.clone())));
+        assert!(sc.move_path(&p, &path("Bill.x")).is_err());
+        assert_eq!(sc.get(&path("Bill.x")), None);
+        assert_eq!(sc.gm.live(), live - 3);
+
+        let (bill, items) = (path("Bill"), path("Bill.items"));
+        assert_eq!(
+            sc.rename(&items, "items"),
+            Err(ActionError::AlreadyExists(items.clone()))
+        );
+        assert_eq!(
+            sc.move_path(&bill, &items),
+            Err(ActionError::MoveIntoSelf(bill.clone()))
+        );
+    }
+
+    #[test]
+    fn test_list_writes() {
+        let mut sc = Scope::new();
+        sc.handle_input(
+            "Bill = {weapons:[{name:\"Dagger\",dam:2},{name:\"Club\",dam:3}]}\n\
+             Bill.weapons.0.dam = 5\nBill.weapons.-1.name = \"Stick\"\n\
+             Bill.weapons.2 = {name:\"Bow\",dam:1}\nBill.weapons.2.dam = Bill.weapons.-1.dam + 1\n\
+             hit = fn(w){ w.weapons.1.dam = 4 }\nhit($Bill)\n",
+        )
+        .unwrap();
+        assert_eq!(sc.get(&path("Bill.weapons.0.dam")), Some(&Value::Num(5)));
+        assert_eq!(
+            sc.get(&path("Bill.weapons.1.name")),
+            Some(&Value::str("Stick"))
+        );
+        assert_eq!(sc.get(&path("Bill.weapons.1.dam")), Some(&Value::Num(4)));
+        assert_eq!(sc.get(&path("Bill.weapons.2.dam")), Some(&Value::Num(2)));
+
+        let live = sc.gm.live();
+        let r = sc.set(&path("Bill.weapons.7"), Value::Num(1));
+        assert_eq!(r, Err(ActionError::OutOfRange("7".to_string(), 3)));
+        assert_eq!(sc.gm.live(), live);
+    }
+
+    #[test]
+    fn test_gc_cycles() {
+        let mut sc = Scope::new();
+        sc.set_gc_every(0);
+        sc.handle_input("a = 1\ngc\n").unwrap();
+        let live = sc.gm.live();
+        sc.handle_input(
+            "Player = {HP:3}\nPlayer.me = $Player\nParty = [$Player]\nParty.1 = $Party\n",
+        )
+        .unwrap();
+        sc.handle_input("del Player\ndel Party\n").unwrap();
+        assert!(sc.gm.live() > live);
+        sc.handle_input("gc\n").unwrap();
+        assert_eq!(sc.gm.live(), live);
+        assert_eq!(sc.get(&path("a")), Some(&Value::Num(1)));
+
+        sc.handle_input("gc every 1\nRat = {HP:2}\nRat.me = $Rat\ndel Rat\n")
+            .unwrap();
+        assert_eq!(sc.gc_every, 1);
+        assert_eq!(sc.gm.live(), live);
+    }
+
+    #[test]
+    fn test_weak_refs() {
+        let mut sc = Scope::new();
+        sc.handle_input(
+            "Rat = {HP:5}\nPlayer = {HP:10}\nPlayer.target = &Rat\nt = Player.target.HP\nPlayer.target.HP = 4\n",
+        )
+        .unwrap();
+        assert_eq!(sc.get(&path("t")), Some(&Value::Num(5)));
+        assert_eq!(sc.get(&path("Rat.HP")), Some(&Value::Num(4)));
+        let live = sc.gm.live();
+        sc.handle_input("del Rat\ngone = Player.target == null\n")
+            .unwrap();
+        assert!(sc.gm.live() < live);
+        assert_eq!(sc.get(&path("Player.target")), Some(&Value::Null));
+        assert_eq!(sc.get(&path("gone")), Some(&Value::Bool(true)));
+        assert!(sc.audit().is_clean());
+    }
+
+    #[test]
+    fn test_links() {
+        let mut sc = Scope::new();
+        sc.handle_input(
+            "Armory = {Longsword:{Dam:8}}\nBill = {HP:3}\nBill.weapon -> Armory.Longsword\nd = Bill.weapon.Dam\n",
+        )
+        .unwrap();
+        assert_eq!(sc.get(&path("d")), Some(&Value::Num(8)));
+        sc.handle_input("Armory.Longsword = {Dam:10}\nd = Bill.weapon.Dam\nBill.weapon.Dam = 12\n")
+            .unwrap();
+        assert_eq!(sc.get(&path("d")), Some(&Value::Num(10)));
+        assert_eq!(sc.get(&path("Armory.Longsword.Dam")), Some(&Value::Num(12)));
+
+        //links made in a fn stay in it, and can point at its locals
+        sc.handle_input(
+            "f = fn(){\n tmp = {Dam:1}\n x -> Armory.Longsword\n y -> tmp\n x.Dam + y.Dam\n}\nr = f()\n",
+        )
+        .unwrap();
+        assert_eq!(sc.get(&path("r")), Some(&Value::Num(13)));
+        assert_eq!(sc.get(&path("x")), None);
+        assert_eq!(sc.get(&path("y")), None);
+
+        sc.handle_input("a -> b\nb -> a\n").unwrap();
+        assert_eq!(sc.get(&path("a")), None);
+        match sc.lookup(&path("a")) {
+            Err(ActionError::LinkCycle(_)) => {}
+            r => panic!("expected a link cycle, got {:?}", r),
+        }
+        assert_eq!(
+            sc.lookup(&path("nothing")).map(|_| ()),
+            Err(ActionError::PathNotFound(path("nothing")))
+        );
+        assert!(sc.link(&path("c"), &path("c.d")).is_err());
+    }
+
+    #[test]
+    fn test_spawn() {
+        let mut sc = Scope::new();
+        sc.handle_input(
+            "Monster = {Dam:2}\nRat = new Monster\nRat.HP = 5\nRat.Bag = [1]\nspawn 3 Rat\nRat2.HP = 1\nRat2.Bag.1 = 2\nc = clone(Rat)\nc.HP = 7\n",
+        )
+        .unwrap();
+        assert_eq!(sc.get(&path("Rat1.HP")), Some(&Value::Num(5)));
+        assert_eq!(sc.get(&path("Rat2.HP")), Some(&Value::Num(1)));
+        assert_eq!(sc.get(&path("Rat3.HP")), Some(&Value::Num(5)));
+        assert_eq!(sc.get(&path("Rat.HP")), Some(&Value::Num(5)));
+        assert_eq!(sc.get(&path("c.HP")), Some(&Value::Num(7)));
+        assert_eq!(sc.get(&path("Rat2.Bag.1")), Some(&Value::Num(2)));
+        assert_eq!(sc.get(&path("Rat.Bag.1")), None);
+
+        sc.handle_input("Monster.Dam = 4\n").unwrap();
+        assert_eq!(sc.get(&path("Rat3.Dam")), Some(&Value::Num(4)));
+        assert!(sc.audit().is_clean());
+
+        assert_eq!(
+            sc.spawn(&path("Rat"), 4),
+            Err(ActionError::AlreadyExists(path("Rat1")))
+        );
+        assert_eq!(sc.get(&path("Rat4")), None);
+        assert_eq!(sc.get(&path("Rat2.HP")), Some(&Value::Num(1)));
+        for n in &[0, -2, MAX_SPAWN as isize + 1] {
+            assert_eq!(
+                sc.spawn(&path("Monster"), *n),
+                Err(ActionError::SpawnCount {
+                    max: MAX_SPAWN,
+                    got: *n
+                })
+            );
+        }
+
+        //links and weak refs within the spawned value follow the copy
+        sc.handle_input(
+            "Bat = {HP:3}\nBat.Me -> Bat.HP\nBat.Self = &Bat\nBat.Out -> Monster.Dam\nspawn 2 Bat\nBat1.HP = 9\n",
+        )
+        .unwrap();
+        assert_eq!(sc.get(&path("Bat1.Me")), Some(&Value::Num(9)));
+        assert_eq!(sc.get(&path("Bat2.Me")), Some(&Value::Num(3)));
+        assert_eq!(sc.get(&path("Bat1.Self.HP")), Some(&Value::Num(9)));
+        assert_eq!(sc.get(&path("Bat.Self.HP")), Some(&Value::Num(3)));
+        assert_eq!(sc.get(&path("Bat2.Out")), Some(&Value::Num(4)));
+        assert!(sc.audit().is_clean());
+    }
+
+    #[test]
+    fn test_audit() {
+        let mut sc = Scope::new();
+        sc.handle_input(
+            "Rat = {HP:5}\nr = $Rat\nr.HP = 3\nl = [1,2,3]\nt = sum(l.1..3)\nfor x in l { y = x }\nf = fn(a){ a * 2 }\nn = f(4)\nmem\n",
+        )
+        .unwrap();
+        assert!(!sc.mem_asked);
+        let a = sc.audit();
+        assert!(a.is_clean(), "{:?}", a);
+        assert_eq!(a.stats.live + a.stats.free, a.stats.slots);
+
+        let leak = sc.gm.push(Value::Num(5));
+        let g = sc.gm.push(Value::Num(1));
+        let stale = g.clone_weak();
+        sc.gm.drop_ref(g);
+        sc.set(&path("bad"), Value::List(vec![stale])).unwrap();
+        let a = sc.audit();
+        assert_eq!(a.unreachable, 1);
+        assert_eq!(a.dangling, vec![path("bad.0")]);
+        sc.gm.drop_ref(leak);
+    }
+}
@@ -3,10 +3,16 @@ use crate::ecs_ish::{GenData, GenManager};
 use crate::error::ActionError;
 use crate::expr::Expr;
 //use crate::scope::Scope;
-use crate::proto::ProtoNode;
-use std::cmp::{Ordering, PartialOrd};
+use crate::proto::{Proto, ProtoNode};
+use std::cmp::Ordering;
 use std::collections::BTreeMap;
 
+/// How deep comparisons follow nested values, protects against reference cycles
+const MAX_CMP_DEPTH: usize = 64;
+/// Printed in place of a handle whose value has been freed
+const DANGLING: &str = "<dangling>";
+
+/// PartialEq compares handles, use deep_eq to compare contents
 #[derive(Debug, PartialEq)]
 pub enum Value {
     Null,
@@ -14,6 +20,10 @@ pub enum Value {
     Num(isize),
     Str(String),
     Ref(GenData),
+    /// Does not keep its target alive, and reads as Null once the target is freed
+    Weak(GenData),
+    /// Found again from the root each time it is read, see Scope::link
+    Link(Proto),
     List(Vec<GenData>),
     Map(BTreeMap<ProtoNode, GenData>),
     ExprDef(Vec<String>, Expr),
@@ -43,15 +53,14 @@ impl Value {
             Num(n) => n.to_string(),
             Map(t) => {
                 let mut res = String::new();
-                for (k, vg) in t {
+                for (k, vg) in t.iter().filter(|(k, _)| !k.is_doc()) {
                     res.push('\n');
                     res.extend((0..depth).map(|_| ' '));
-                    if let Some(v) = gm.get(vg) {
-                        res.push_str(&k.as_string());
-                        res.push(':');
-                        res.push_str(&v.print(depth + 1, gm));
-                    } else {
-                        res.push_str("Freeed Pointer Err");
+                    res.push_str(&k.as_string());
+                    res.push(':');
+                    match gm.get(vg) {
+                        Some(v) => res.push_str(&v.print(depth + 1, gm)),
+                        None => res.push_str(DANGLING),
                     }
                 }
                 res
@@ -67,7 +76,7 @@ impl Value {
                     if let Some(v) = gm.get(vg) {
                         res.push_str(&v.print(0, gm));
                     } else {
-                        res.push_str("Freeed Pointer Err");
+                        res.push_str(DANGLING);
                     }
                 }
                 res.push(']');
@@ -80,138 +89,242 @@ impl Value {
                 if let Some(v) = gm.get(vg) {
                     res.push_str(&v.print(0, gm));
                 } else {
-                    res.push_str("Freeed Pointer Err");
+                    res.push_str(DANGLING);
                 }
                 res
             }
+            //Only a type for containers, as weak references often form cycles
+            Weak(vg) => match gm.get(vg) {
+                Some(v @ Map(_)) | Some(v @ List(_)) | Some(v @ Ref(_)) | Some(v @ Weak(_)) => {
+                    format!("&{}", v.type_name())
+                }
+                Some(v) => format!("&{}", v.print(0, gm)),
+                None => "NULL".to_string(),
+            },
+            Link(p) => format!("->{}", p),
         }
     }
 
     pub fn child_ref(&self, pn: &ProtoNode) -> Option<&GenData> {
         match self {
             Value::Map(t) => t.get(pn),
-            Value::List(v) => {
-                if let ProtoNode::Num(n) = pn {
-                    v.get(*n)
-                } else {
-                    None
-                }
-            }
+            Value::List(v) => pn.list_index(v.len()).and_then(|i| v.get(i)),
             Value::Ref(v) => Some(v),
             _ => None,
         }
     }
 
+    /// The "proto" field of a map, which reads fall back on
+    pub fn proto(&self) -> Option<&GenData> {
+        match self {
+            Value::Map(t) => t.get(&ProtoNode::str("proto")),
+            _ => None,
+        }
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Null => "null",
+            Value::Bool(_) => "bool",
+            Value::Num(_) => "num",
+            Value::Str(_) => "str",
+            Value::Ref(_) => "ref",
+            Value::Weak(_) => "weak",
+            Value::Link(_) => "link",
+            Value::List(_) => "list",
+            Value::Map(_) => "map",
+            Value::ExprDef(_, _) => "expr",
+            Value::FuncDef(_, _) => "fn",
+        }
+    }
+
     /// Logic Or included
     pub fn try_add(self, rhs: Value, gm: &mut GenManager) -> Result<Value, ActionError> {
         use Value::*;
-        match self {
-            Bool(a) => match rhs {
-                Bool(b) => Ok(Bool(a || b)),
-                _ => Err(ActionError::new("Bool only adds to bool is OR op")),
-            },
-            Num(a) => match rhs {
-                Num(b) => Ok(Num(a + b)),
-                _ => Err(ActionError::new("Cannot add non num to num")),
-            },
-            Str(mut a) => match rhs {
-                Str(b) => {
-                    a.push_str(&b.to_string());
-                    Ok(Str(a))
-                }
-                _ => Err(ActionError::new("Cannot add non str to str")),
-            },
-            List(mut a) => match rhs {
-                List(b) => {
-                    a.extend(b);
-                    Ok(List(a))
-                }
-                _ => {
-                    for i in a {
-                        gm.drop_ref(i);
-                    }
-                    gm.drop(rhs);
-                    Err(ActionError::new("Cannot add non list to list"))
-                }
-            },
-            Map(mut ma) => {
-                if let Map(mb) = rhs {
-                    for (k, v) in mb {
-                        if let Some(d) = ma.insert(k, v) {
-                            gm.drop_ref(d)
-                        }
-                    }
-                    Ok(Value::Map(ma))
-                } else {
-                    for (_, v) in ma {
-                        gm.drop_ref(v);
+        let err = ActionError::mismatch("add", &self, &rhs);
+        match (self, rhs) {
+            (Bool(a), Bool(b)) => Ok(Bool(a || b)),
+            (Num(a), Num(b)) => Ok(Num(a + b)),
+            (Str(mut a), Str(b)) => {
+                a.push_str(&b);
+                Ok(Str(a))
+            }
+            (List(mut a), List(b)) => {
+                a.extend(b);
+                Ok(List(a))
+            }
+            (Map(mut ma), Map(mb)) => {
+                for (k, v) in mb {
+                    if let Some(d) = ma.insert(k, v) {
+                        gm.drop_ref(d)
                     }
-                    gm.drop(rhs);
-                    Err(ActionError::new("Cannot add non map to map"))
                 }
+                Ok(Value::Map(ma))
             }
-            //TODO Map + Map
-            u => {
-                let e = Err(ActionError::new(&format!("Add of {:?} not suppported", u)));
-                gm.drop(u);
-                gm.drop(rhs);
-                e
+            (a, b) => {
+                gm.drop(a);
+                gm.drop(b);
+                Err(err)
             }
         }
     }
 
-    pub fn try_sub(self, rhs: Value) -> Result<Value, ActionError> {
+    pub fn try_sub(self, rhs: Value, gm: &mut GenManager) -> Result<Value, ActionError> {
         use Value::*;
-        match self {
-            Num(a) => match rhs {
-                Num(b) => Ok(Num(a - b)),
-                _ => Err(ActionError::new("Can only sub num from num")),
-            },
-            Str(_) => Err(ActionError::new("Cannot subtract from string")),
-            List(a) => match rhs {
-                List(b) => Ok(List(a.into_iter().filter(|x| !b.contains(&x)).collect())),
-                Ref(v) => Ok(List(a.into_iter().filter(|x| *x != v).collect())),
-                _ => unimplemented!(),
-            },
-            Map(mut t) => match rhs {
-                Str(s) => {
-                    t.remove(&ProtoNode::str(&s));
-                    Ok(Map(t))
+        let err = ActionError::mismatch("subtract", &self, &rhs);
+        match (self, rhs) {
+            (Num(a), Num(b)) => Ok(Num(a - b)),
+            (List(a), List(b)) => {
+                let (gone, keep): (Vec<GenData>, Vec<GenData>) =
+                    a.into_iter().partition(|x| b.contains(&x));
+                for g in gone.into_iter().chain(b) {
+                    gm.drop_ref(g);
                 }
-                _ => Err(ActionError::new("Can only sub str from tree")),
-            },
-            u => Err(ActionError::new(&format!("Sub of {:?} not suppported", u))),
+                Ok(List(keep))
+            }
+            (List(a), Ref(v)) => {
+                let (gone, keep): (Vec<GenData>, Vec<GenData>) =
+                    a.into_iter().partition(|x| *x == v);
+                for g in gone {
+                    gm.drop_ref(g);
+                }
+                gm.drop_ref(v);
+                Ok(List(keep))
+            }
+            (Map(mut t), Str(s)) => {
+                if let Some(g) = t.remove(&ProtoNode::str(&s)) {
+                    gm.drop_ref(g);
+                }
+                Ok(Map(t))
+            }
+            (a, b) => {
+                gm.drop(a);
+                gm.drop(b);
+                Err(err)
+            }
         }
     }
 
     pub fn try_mul(self, rhs: Value) -> Result<Value, ActionError> {
-        match self {
-            Value::Bool(a) => match rhs {
-                Value::Bool(b) => Ok(Value::Bool(a && b)),
-                _ => Err(ActionError::new("Bool can only mul Bool as AND op")),
-            },
-            Value::Num(a) => match rhs {
-                Value::Num(b) => Ok(Value::Num(a * b)),
-                _ => Err(ActionError::new("No mul on non num")),
-            },
-            _ => Err(ActionError::new("No mul on non num")),
+        match (self, rhs) {
+            (Value::Bool(a), Value::Bool(b)) => Ok(Value::Bool(a && b)),
+            (Value::Num(a), Value::Num(b)) => Ok(Value::Num(a * b)),
+            (a, b) => Err(ActionError::mismatch("multiply", &a, &b)),
         }
     }
     pub fn try_div(self, rhs: Value) -> Result<Value, ActionError> {
-        match self {
-            Value::Num(a) => match rhs {
-                Value::Num(0) => Err(ActionError::new("Can't div by zero")),
-                Value::Num(b) => Ok(Value::Num(a / b)),
-                _ => Err(ActionError::new("No div on non ex")),
-            },
-            _ => Err(ActionError::new("No div on non ex")),
+        match (self, rhs) {
+            (Value::Num(_), Value::Num(0)) => Err(ActionError::DivideByZero),
+            (Value::Num(a), Value::Num(b)) => Ok(Value::Num(a / b)),
+            (a, b) => Err(ActionError::mismatch("divide", &a, &b)),
+        }
+    }
+    pub fn try_mod(self, rhs: Value) -> Result<Value, ActionError> {
+        match (self, rhs) {
+            (Value::Num(_), Value::Num(0)) => Err(ActionError::DivideByZero),
+            (Value::Num(a), Value::Num(b)) => Ok(Value::Num(a.rem_euclid(b))),
+            (a, b) => Err(ActionError::mismatch("mod", &a, &b)),
         }
     }
     pub fn try_neg(self) -> Result<Value, ActionError> {
         match self {
             Value::Num(v) => Ok(Value::Num(-v)),
             Value::Bool(b) => Ok(Value::Bool(!b)),
-            _ => Err(ActionError::new("No neg non ex")),
+            v => Err(ActionError::wrong_type("num or bool", &v)),
+        }
+    }
+
+    /// Follows references to the value they end at, a freed weak target is Null
+    pub fn deref<'a>(&'a self, gm: &'a GenManager) -> Result<&'a Value, ActionError> {
+        let mut v = self;
+        loop {
+            v = match v {
+                Value::Ref(g) => gm.get(g).ok_or(ActionError::FreedReference)?,
+                Value::Weak(g) => gm.get(g).unwrap_or(&Value::Null),
+                _ => return Ok(v),
+            }
+        }
+    }
+
+    /// Orders by content through references: strings lexically,
+    /// lists item by item and maps key by key. Different types can't be compared.
+    /// A map's "proto" is compared like any field, so instances are only equal if their protos are,
+    /// but inherited fields are not looked up
+    pub fn deep_cmp(&self, other: &Value, gm: &GenManager) -> Result<Ordering, ActionError> {
+        self.cmp_depth(other, gm, 0)
+    }
+
+    /// Different types are just unequal, but values too deep to compare are an error
+    pub fn deep_eq(&self, other: &Value, gm: &GenManager) -> Result<bool, ActionError> {
+        match self.deep_cmp(other, gm) {
+            Ok(o) => Ok(o == Ordering::Equal),
+            Err(ActionError::TypeMismatch { .. }) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn cmp_depth(
+        &self,
+        other: &Value,
+        gm: &GenManager,
+        depth: usize,
+    ) -> Result<Ordering, ActionError> {
+        use Value::*;
+        //this deep is almost always a cycle, which walking can't tell apart
+        if depth > MAX_CMP_DEPTH {
+            return Err(ActionError::CompareDepth(MAX_CMP_DEPTH));
+        }
+        //the same slot is equal to itself, which also ends a cycle compared with itself
+        let child = |ga: &GenData, gb: &GenData| match (gm.get(ga), gm.get(gb)) {
+            _ if ga == gb => Ok(Ordering::Equal),
+            (Some(a), Some(b)) => a.cmp_depth(b, gm, depth + 1),
+            _ => Err(ActionError::FreedReference),
+        };
+        match (self, other) {
+            (Ref(a), Ref(b)) | (Weak(a), Weak(b)) if a == b => return Ok(Ordering::Equal),
+            _ => {}
+        }
+        match (self.deref(gm)?, other.deref(gm)?) {
+            (Null, Null) => Ok(Ordering::Equal),
+            (Bool(a), Bool(b)) => Ok(a.cmp(b)),
+            (Num(a), Num(b)) => Ok(a.cmp(b)),
+            (Str(a), Str(b)) => Ok(a.cmp(b)),
+            (List(a), List(b)) => {
+                for (ga, gb) in a.iter().zip(b) {
+                    match child(ga, gb)? {
+                        Ordering::Equal => {}
+                        o => return Ok(o),
+                    }
+                }
+                Ok(a.len().cmp(&b.len()))
+            }
+            (Map(a), Map(b)) => {
+                let (a, b) = (data_items(a), data_items(b));
+                let (la, lb) = (a.len(), b.len());
+                for ((ka, ga), (kb, gb)) in a.into_iter().zip(b) {
+                    match ka.cmp(kb) {
+                        Ordering::Equal => {}
+                        o => return Ok(o),
+                    }
+                    match child(ga, gb)? {
+                        Ordering::Equal => {}
+                        o => return Ok(o),
+                    }
+                }
+                Ok(la.cmp(&lb))
+            }
+            (a, b) if a == b => Ok(Ordering::Equal),
+            (a, b) => Err(ActionError::mismatch("compare", a, b)),
+        }
+    }
+
+    /// The handles this value holds, as gen_drop gives them up
+    pub fn children(&self) -> Vec<&GenData> {
+        match self {
+            Value::List(v) => v.iter().collect(),
+            Value::Map(m) => m.values().collect(),
+            Value::Ref(r) => vec![r],
+            _ => Vec::new(),
         }
     }
 
@@ -231,6 +344,8 @@ impl Value {
             Value::Num(n) => Value::Num(*n),
             Value::Str(s) => Value::Str(s.clone()),
             Value::Ref(gd) => Value::Ref(gd.clone_weak()),
+            Value::Weak(gd) => Value::Weak(gd.clone_weak()),
+            Value::Link(p) => Value::Link(p.clone()),
             Value::List(v) => Value::List(v.iter().map(|gd| gd.clone_weak()).collect()),
             Value::Map(m) => {
                 let mut res = BTreeMap::new();
@@ -275,11 +390,31 @@ impl Value {
     ) -> Result<Option<GenData>, ActionError> {
         match self {
             Value::Map(m) => Ok(m.insert(pn, gd)),
-            Value::Ref(_) => unimplemented!("Try Give Child needs Ref"),
-            Value::List(_) => unimplemented!("Try Give Child needs List"),
-            _ => Err(ActionError::new("Not childable type")),
+            //writing one past the end appends
+            Value::List(l) => match pn.list_index(l.len()) {
+                Some(i) if i < l.len() => Ok(Some(std::mem::replace(&mut l[i], gd))),
+                Some(i) if i == l.len() => {
+                    l.push(gd);
+                    Ok(None)
+                }
+                _ => Err(ActionError::OutOfRange(pn.as_string(), l.len())),
+            },
+            //Refs are followed by the scope before writing
+            v => Err(ActionError::wrong_type("map or list", v)),
+        }
+    }
+    /// Removes the child, giving back its reference
+    pub fn take_child(&mut self, pn: &ProtoNode) -> Option<GenData> {
+        match (self, pn) {
+            (Value::Map(m), pn) => m.remove(pn),
+            (Value::List(l), pn) => match pn.list_index(l.len()) {
+                Some(i) if i < l.len() => Some(l.remove(i)),
+                _ => None,
+            },
+            _ => None,
         }
     }
+
     pub fn try_give_child(
         &mut self,
         pn: &ProtoNode,
@@ -295,25 +430,20 @@ impl Value {
                     return Ok((true, g_res));
                 }
             }
-            Value::Ref(_r) => unimplemented!("Try Give Child needs Ref"),
-            Value::List(_r) => unimplemented!("Try Give Child needs List"),
-            _ => return Err(ActionError::new("Could not give Child")),
+            Value::List(l) => match pn.list_index(l.len()) {
+                Some(i) if i < l.len() => Ok((false, l[i].clone_weak())),
+                Some(i) if i == l.len() => {
+                    l.push(gd);
+                    Ok((true, g_res))
+                }
+                _ => Err(ActionError::OutOfRange(pn.as_string(), l.len())),
+            },
+            v => return Err(ActionError::wrong_type("map or list", v)),
         }
     }
 }
 
-impl PartialOrd for Value {
-    fn partial_cmp(&self, other: &Value) -> Option<Ordering> {
-        use Value::*;
-        match self {
-            //TODO allow other comparisons
-            Num(a) => {
-                if let Num(b) = other {
-                    return a.partial_cmp(b);
-                }
-            }
-            _ => return None,
-        }
-        None
-    }
+/// The fields of a map without its docs, which don't change what it is
+fn data_items(m: &BTreeMap<ProtoNode, GenData>) -> Vec<(&ProtoNode, &GenData)> {
+    m.iter().filter(|(k, _)| !k.is_doc()).collect()
 }